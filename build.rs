@@ -0,0 +1,4 @@
+fn main() {
+    // Used by `self-update` to pick the release artifact for this platform
+    println!("cargo:rustc-env=VIMFMI_TARGET={}", std::env::var("TARGET").unwrap());
+}
@@ -0,0 +1,340 @@
+use std::cell::Cell;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use argon2::Argon2;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde::{Serialize, Deserialize};
+use tempfile::NamedTempFile;
+use tracing::warn;
+
+use crate::config::validate_name;
+use crate::controller::User;
+
+/// The users that have been set up in a profile's data directory. Each one is stored in
+/// `accounts/<name>.json`, and the name of the selected one is kept in `current_account`.
+///
+/// Account files are only readable by their owner. The token in them can optionally be encrypted
+/// with a passphrase, which is then read from `VIMFMI_PASSPHRASE` or asked for in the terminal.
+///
+pub struct Accounts {
+    data_dir: PathBuf,
+    migrated: Cell<bool>,
+}
+
+impl Accounts {
+    pub fn new(data_dir: &Path) -> ::anyhow::Result<Self> {
+        Ok(Self { data_dir: data_dir.to_path_buf(), migrated: Cell::new(false) })
+    }
+
+    pub fn list(&self) -> ::anyhow::Result<Vec<String>> {
+        self.migrate()?;
+
+        let dir = self.accounts_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// The name of the selected account. If none has been selected, but there's only one, that's
+    /// the one.
+    ///
+    pub fn current_name(&self) -> ::anyhow::Result<Option<String>> {
+        self.migrate()?;
+
+        let path = self.data_dir.join("current_account");
+
+        if path.exists() {
+            let name = fs::read_to_string(path)?.trim().to_owned();
+            if self.account_path(&name)?.exists() {
+                return Ok(Some(name));
+            }
+        }
+
+        let mut names = self.list()?;
+        if names.len() == 1 {
+            Ok(names.pop())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The user with the given name, or the selected one if there's no name.
+    pub fn read(&self, name: Option<&str>) -> ::anyhow::Result<Option<User>> {
+        self.migrate()?;
+
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => match self.current_name()? {
+                Some(name) => name,
+                None => return Ok(None),
+            },
+        };
+
+        let path = self.account_path(&name)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        check_permissions(&path)?;
+
+        let stored: StoredUser = serde_json::from_reader(fs::File::open(path)?)?;
+        let token = match (stored.token, stored.encrypted_token) {
+            (_, Some(encrypted)) => encrypted.decrypt(&read_passphrase(false)?)?,
+            (Some(token), None) => token,
+            (None, None) => return Err(anyhow!("Акаунтът `{}` няма записан токен", name)),
+        };
+
+        Ok(Some(User { id: stored.id, faculty_number: stored.faculty_number, token }))
+    }
+
+    /// Writes the user atomically, encrypting its token if there's a passphrase.
+    pub fn write(&self, name: &str, user: &User, passphrase: Option<&str>) -> ::anyhow::Result<()> {
+        let path = self.account_path(name)?;
+
+        let (token, encrypted_token) = match passphrase {
+            Some(passphrase) => (None, Some(EncryptedToken::encrypt(&user.token, passphrase)?)),
+            None => (Some(user.token.clone()), None),
+        };
+        let stored = StoredUser {
+            id: user.id,
+            faculty_number: user.faculty_number.clone(),
+            token,
+            encrypted_token,
+        };
+
+        create_private_dir(&self.accounts_dir())?;
+        write_private(&path, &serde_json::to_string(&stored)?)?;
+
+        Ok(())
+    }
+
+    pub fn switch(&self, name: &str) -> ::anyhow::Result<()> {
+        self.migrate()?;
+
+        if !self.account_path(name)?.exists() {
+            return Err(anyhow!("Няма акаунт `{}`", name));
+        }
+
+        fs::create_dir_all(&self.data_dir)?;
+        fs::write(self.data_dir.join("current_account"), name)?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> ::anyhow::Result<()> {
+        self.migrate()?;
+
+        let path = self.account_path(name)?;
+        if !path.exists() {
+            return Err(anyhow!("Няма акаунт `{}`", name));
+        }
+
+        let current_path = self.data_dir.join("current_account");
+        if current_path.exists() && fs::read_to_string(&current_path)?.trim() == name {
+            fs::remove_file(current_path)?;
+        }
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    fn accounts_dir(&self) -> PathBuf {
+        self.data_dir.join("accounts")
+    }
+
+    fn account_path(&self, name: &str) -> ::anyhow::Result<PathBuf> {
+        validate_name("акаунт", name)?;
+        Ok(self.accounts_dir().join(format!("{}.json", name)))
+    }
+
+    // Only done when accounts are actually needed, so a broken `user.json` doesn't get in the way
+    // of commands like `config` or `doctor`.
+    fn migrate(&self) -> ::anyhow::Result<()> {
+        // Set beforehand, since the migration itself uses the other methods
+        if self.migrated.replace(true) {
+            return Ok(());
+        }
+
+        self.migrate_single_user()
+    }
+
+    // Older versions kept a single `user.json` in the data directory. It becomes an account named
+    // after its faculty number.
+    fn migrate_single_user(&self) -> ::anyhow::Result<()> {
+        let legacy_path = self.data_dir.join("user.json");
+        if !legacy_path.exists() {
+            return Ok(());
+        }
+
+        let user: User = serde_json::from_reader(fs::File::open(&legacy_path)?)?;
+        if let Err(e) = validate_name("акаунт", &user.faculty_number) {
+            warn!(
+                "{} не може да стане акаунт, активирай се отново с `vim-fmi setup <token>`: {:#}",
+                legacy_path.display(), e,
+            );
+            return Ok(());
+        }
+
+        self.write(&user.faculty_number, &user, None)?;
+        if self.current_name()?.is_none() {
+            self.switch(&user.faculty_number)?;
+        }
+        fs::remove_file(legacy_path)?;
+
+        Ok(())
+    }
+}
+
+/// Reads the passphrase for encrypted tokens from `VIMFMI_PASSPHRASE` or asks for it in the
+/// terminal, the second time around if it needs to be confirmed.
+///
+pub fn read_passphrase(confirm: bool) -> ::anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var("VIMFMI_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("Парола за токена: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Повтори паролата: ")? {
+        return Err(anyhow!("Паролите не съвпадат"));
+    }
+
+    Ok(passphrase)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredUser {
+    id: u32,
+    faculty_number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_token: Option<EncryptedToken>,
+}
+
+/// A token encrypted with ChaCha20-Poly1305, using a key derived from the passphrase with Argon2.
+/// All fields are base64-encoded.
+///
+#[derive(Debug, Deserialize, Serialize)]
+struct EncryptedToken {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedToken {
+    fn encrypt(token: &str, passphrase: &str) -> ::anyhow::Result<Self> {
+        let mut salt = [0_u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, token.as_bytes()).
+            map_err(|_| anyhow!("Токенът не можа да бъде криптиран"))?;
+
+        Ok(Self {
+            salt: BASE64.encode(salt),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        })
+    }
+
+    fn decrypt(&self, passphrase: &str) -> ::anyhow::Result<String> {
+        let salt = BASE64.decode(&self.salt)?;
+        let nonce = BASE64.decode(&self.nonce)?;
+        let ciphertext = BASE64.decode(&self.ciphertext)?;
+
+        if nonce.len() != 12 {
+            return Err(anyhow!("Криптираният токен е повреден"));
+        }
+
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+        let token = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice()).
+            map_err(|_| anyhow!("Грешна парола за токена"))?;
+
+        Ok(String::from_utf8(token)?)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> ::anyhow::Result<Key> {
+    let mut key = Key::default();
+    Argon2::default().
+        hash_password_into(passphrase.as_bytes(), salt, &mut key).
+        map_err(|e| anyhow!("Couldn't derive key: {}", e))?;
+
+    Ok(key)
+}
+
+// Writes to a temporary file next to the target and renames it, so a crash can't leave a
+// half-written file behind. The temporary file is created readable only by its owner.
+fn write_private(path: &Path, contents: &str) -> ::anyhow::Result<()> {
+    let dir = path.parent().ok_or_else(|| anyhow!("No parent directory for {}", path.display()))?;
+    let mut file = NamedTempFile::new_in(dir)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.as_file().set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(contents.as_bytes())?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+
+    Ok(())
+}
+
+fn create_private_dir(dir: &Path) -> ::anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(())
+}
+
+/// Account files readable by others aren't loaded at all, the token in them might have been seen.
+/// The ones migrated from older versions are written anew, so they're private already.
+///
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> ::anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(anyhow!(
+            "Файлът {} може да бъде прочетен от други потребители на компютъра (права {:o}). \
+            Оправи правата с `chmod 600 {}` и пробвай пак.",
+            path.display(), mode & 0o777, path.display(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> ::anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use directories::ProjectDirs;
+use serde::{Serialize, Deserialize};
+use tracing::warn;
+use url::Url;
+
+use crate::vim;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+pub fn project_dirs() -> ::anyhow::Result<ProjectDirs> {
+    ProjectDirs::from("bg", "fmi", "vim-fmi-cli").
+        ok_or_else(|| anyhow!("Couldn't initialize project dir"))
+}
+
+pub fn default_host() -> Url {
+    // Unwrap: these are valid URLs
+    if cfg!(debug_assertions) {
+        Url::parse("http://localhost:3000").unwrap()
+    } else {
+        Url::parse("https://vim-fmi.bg").unwrap()
+    }
+}
+
+/// The contents of `config.toml` in the project's config directory, for example:
+///
+/// ```toml
+/// profile = "staging"
+/// novimrc = true
+/// diff = "unified"
+///
+/// [vim]
+/// preference = ["vim", "nvim"]
+///
+/// [meta]
+/// devicename = false
+///
+/// [profiles.staging]
+/// host = "https://staging.vim-fmi.bg"
+/// ```
+///
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub profile: Option<String>,
+    pub host: Option<Url>,
+    pub novimrc: bool,
+    pub diff: DiffStyle,
+    pub color: ColorChoice,
+    pub language: Language,
+    pub vim: VimConfig,
+    pub meta: MetaConfig,
+    pub http: HttpConfig,
+    pub update: UpdateConfig,
+    pub profiles: BTreeMap<String, ProfileConfig>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub host: Option<Url>,
+}
+
+/// Without an `executable`, the first one from `preference` that's in the `$PATH` is used,
+/// skipping GUI ones like `gvim` if the `interface` is `terminal` and terminal ones if it's `gui`.
+///
+/// Only a few `args` and `env` variables are allowed, ones that change how Vim looks, like `-T` or
+/// `-geometry` and `TERM`. The `columns` and `lines` are set after the vimrc, if both are given.
+///
+/// The `time_limit` is in seconds. Tasks can come with their own limit from the server, and then
+/// the shorter of the two is used. A limit of 0 is the same as none.
+///
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VimConfig {
+    pub executable: Option<String>,
+    pub preference: Vec<String>,
+    pub interface: VimInterface,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub columns: Option<u32>,
+    pub lines: Option<u32>,
+    pub time_limit: Option<u64>,
+}
+
+impl Default for VimConfig {
+    fn default() -> Self {
+        Self {
+            executable: None,
+            preference: ["mvim", "gvim", "vim", "nvim"].map(String::from).to_vec(),
+            interface: VimInterface::default(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            columns: None,
+            lines: None,
+            time_limit: None,
+        }
+    }
+}
+
+/// Which parts of the machine's information are sent to the server along with setup and
+/// solutions.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetaConfig {
+    pub username: bool,
+    pub devicename: bool,
+    pub platform: bool,
+}
+
+impl Default for MetaConfig {
+    fn default() -> Self {
+        Self { username: true, devicename: true, platform: true }
+    }
+}
+
+/// Timeouts are in seconds. Requests that fail because of the network or get a 429 or 5xx response
+/// are retried up to `retries` times, waiting longer each time.
+///
+/// Without a `proxy`, the one from `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` is used, skipping the
+/// hosts in `NO_PROXY`. With one, `no_proxy` takes the place of `NO_PROXY`. The certificates in the
+/// `ca_cert` PEM file are trusted in addition to the built-in ones, for networks that inspect TLS
+/// traffic.
+///
+/// A request that an older server rejects is sent again with the token in the URL or the body, the
+/// way servers that don't know about the `Authorization` header expect it. Servers are recognized
+/// as older if they ask for this in `/api/client_version.json` or don't have it at all, and
+/// `legacy_auth` does it for any server.
+///
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub connect_timeout: u64,
+    pub timeout: u64,
+    pub retries: u32,
+    pub proxy: Option<Url>,
+    pub no_proxy: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub legacy_auth: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10,
+            timeout: 30,
+            retries: 3,
+            proxy: None,
+            no_proxy: None,
+            ca_cert: None,
+            legacy_auth: false,
+        }
+    }
+}
+
+/// Where `self-update` looks for new versions. The response should look like the one from GitHub's
+/// API, so a local file server with a `release.json` will do for testing.
+///
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpdateConfig {
+    pub release_url: Url,
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        // Unwrap: this is a valid URL
+        let release_url =
+            Url::parse("https://api.github.com/repos/AndrewRadev/vim-fmi-cli/releases/latest").unwrap();
+
+        Self { release_url }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffStyle {
+    /// Every line of the expected and actual output, marked with `-`, `+` or ` `
+    #[default]
+    Full,
+    /// Only the changed lines with a few lines of context around them
+    Unified,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VimInterface {
+    /// Whichever comes first in the preference list
+    #[default]
+    Any,
+    Terminal,
+    Gui,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The language of the messages that come from the server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Language {
+    #[default]
+    Bg,
+    En,
+}
+
+impl Language {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::Bg => "bg",
+            Language::En => "en",
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> ::anyhow::Result<PathBuf> {
+        Ok(project_dirs()?.config_dir().join("config.toml"))
+    }
+
+    pub fn load() -> ::anyhow::Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let (config, unknown_keys) = parse(&contents).
+            map_err(|e| anyhow!("Невалиден конфигурационен файл {}: {}", path.display(), e))?;
+
+        for key in unknown_keys {
+            warn!("Непозната настройка `{}` в {}, пропускам я", key, path.display());
+        }
+
+        Ok(config)
+    }
+
+    /// Picks the profile with the given name, or the one set in the config file, or the default
+    /// one. Every profile apart from the default keeps its user in a separate directory.
+    ///
+    pub fn profile(&self, name: Option<&str>) -> ::anyhow::Result<Profile> {
+        let name = name.or(self.profile.as_deref()).unwrap_or(DEFAULT_PROFILE);
+        let profile_config = self.profiles.get(name);
+
+        if profile_config.is_none() && name != DEFAULT_PROFILE {
+            return Err(anyhow!("Няма профил `{}` в {}", name, Self::path()?.display()));
+        }
+        validate_name("профил", name)?;
+
+        let host = profile_config.
+            and_then(|p| p.host.clone()).
+            or_else(|| self.host.clone()).
+            unwrap_or_else(default_host);
+
+        let data_dir =
+            if name == DEFAULT_PROFILE {
+                project_dirs()?.data_dir().to_path_buf()
+            } else {
+                project_dirs()?.data_dir().join("profiles").join(name)
+            };
+
+        Ok(Profile { name: name.to_owned(), host, data_dir })
+    }
+
+    /// All set values, including defaults, as `key = value` pairs with dotted keys like
+    /// `vim.executable`.
+    ///
+    pub fn list(&self) -> ::anyhow::Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        flatten_value(String::new(), &toml::Value::try_from(self)?, &mut pairs);
+        Ok(pairs)
+    }
+
+    pub fn get(&self, key: &str) -> ::anyhow::Result<Option<String>> {
+        Ok(self.list()?.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+
+    /// Sets the given dotted key in the config file, keeping everything else in it as-is. The
+    /// value is parsed as TOML if possible (`true`, `10`, `["-T", "xterm"]`), otherwise it's taken
+    /// as a string.
+    ///
+    pub fn set(key: &str, value: &str) -> ::anyhow::Result<()> {
+        let path = Self::path()?;
+        let mut table: toml::Table =
+            if path.exists() {
+                toml::from_str(&fs::read_to_string(&path)?)?
+            } else {
+                toml::Table::new()
+            };
+
+        let value = toml::from_str::<toml::Table>(&format!("value = {}", value)).
+            ok().
+            and_then(|mut t| t.remove("value")).
+            unwrap_or_else(|| toml::Value::String(value.to_owned()));
+
+        let mut segments: Vec<&str> = key.split('.').collect();
+        // Unwrap: split always returns at least one segment
+        let last = segments.pop().unwrap();
+        let mut current = &mut table;
+
+        for segment in segments {
+            current = current.
+                entry(segment).
+                or_insert_with(|| toml::Value::Table(toml::Table::new())).
+                as_table_mut().
+                ok_or_else(|| anyhow!("`{}` не е секция в конфигурацията", segment))?;
+        }
+        current.insert(last.to_owned(), value);
+
+        // Validate before writing anything, so a mistake shows up now and not in the next session
+        let (config, unknown_keys) = parse(&toml::to_string(&table)?).
+            map_err(|e| anyhow!("Невалидна стойност за `{}`: {}", key, e))?;
+        let is_unknown = |unknown_key: &String| {
+            key == unknown_key || key.starts_with(&format!("{}.", unknown_key))
+        };
+        if unknown_keys.iter().any(is_unknown) {
+            return Err(anyhow!("Няма настройка `{}`, виж `vim-fmi config list`", key));
+        }
+        vim::validate_args(&config.vim.args)?;
+        vim::validate_env(&config.vim.env)?;
+
+        fs::create_dir_all(path.parent().unwrap_or(&path))?;
+        fs::write(&path, toml::to_string_pretty(&table)?)?;
+
+        Ok(())
+    }
+}
+
+/// Keys this version doesn't know about are left out instead of failing, since they could be
+/// from a newer version. They're returned along with the config.
+///
+fn parse(contents: &str) -> Result<(Config, Vec<String>), toml::de::Error> {
+    let mut unknown_keys = Vec::new();
+    let config = serde_ignored::deserialize(toml::Deserializer::new(contents), |path| {
+        unknown_keys.push(path.to_string());
+    })?;
+
+    Ok((config, unknown_keys))
+}
+
+fn flatten_value(prefix: String, value: &toml::Value, pairs: &mut Vec<(String, String)>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten_value(key, value, pairs);
+            }
+        },
+        toml::Value::String(string) => pairs.push((prefix, string.clone())),
+        other => pairs.push((prefix, other.to_string())),
+    }
+}
+
+/// Names of profiles and accounts end up in file paths, so they're kept simple.
+pub fn validate_name(kind: &str, name: &str) -> ::anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow!("Името на {} може да съдържа само букви, цифри, `-` и `_`: `{}`", kind, name));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub host: Url,
+    pub data_dir: PathBuf,
+}
+
+impl Profile {
+    /// Points the profile to another site. Accounts belong to the site they were set up on, so a
+    /// different one gets a data directory of its own, inside the profile's.
+    ///
+    pub fn override_host(&mut self, host: &Url) {
+        if *host == self.host {
+            return;
+        }
+
+        let name = format!(
+            "{}-{}-{}",
+            host.scheme(),
+            host.host_str().unwrap_or_default(),
+            host.port_or_known_default().unwrap_or_default(),
+        );
+        let name: String = name.chars().
+            map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).
+            collect();
+
+        self.data_dir = self.data_dir.join("hosts").join(name);
+        self.host = host.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> Profile {
+        Profile {
+            name: String::from(DEFAULT_PROFILE),
+            host: Url::parse("https://vim-fmi.bg").unwrap(),
+            data_dir: PathBuf::from("data"),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_skipped() {
+        let (config, unknown_keys) = parse("difff = \"unified\"\nnovimrc = true\n[vim]\nflavour = 1\n").unwrap();
+
+        assert!(config.novimrc);
+        assert_eq!(config.diff, DiffStyle::Full);
+        assert_eq!(unknown_keys, ["difff", "vim.flavour"]);
+    }
+
+    #[test]
+    fn invalid_values_still_fail() {
+        assert!(parse("diff = 5\n").is_err());
+    }
+
+    #[test]
+    fn same_host_keeps_the_data_dir() {
+        let mut profile = profile();
+        profile.override_host(&Url::parse("https://vim-fmi.bg/").unwrap());
+
+        assert_eq!(profile.data_dir, PathBuf::from("data"));
+    }
+
+    #[test]
+    fn other_host_gets_its_own_data_dir() {
+        let mut profile = profile();
+        profile.override_host(&Url::parse("http://localhost:3000").unwrap());
+
+        assert_eq!(profile.host.as_str(), "http://localhost:3000/");
+        assert_eq!(profile.data_dir, PathBuf::from("data/hosts/http-localhost-3000"));
+
+        let mut profile = self::profile();
+        profile.override_host(&Url::parse("http://[::1]:3000").unwrap());
+        assert_eq!(profile.data_dir, PathBuf::from("data/hosts/http-___1_-3000"));
+    }
+}
@@ -1,15 +1,35 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
 use std::io::Write;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use url::Url;
+use once_cell::unsync::OnceCell;
+use url::{Host, Url};
 use tempfile::TempDir;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use base64::{Engine as _};
-use directories::ProjectDirs;
+use tracing::debug;
+
+use reqwest::{Certificate, NoProxy, Proxy, StatusCode};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+
+use crate::config::{Config, HttpConfig, Language, MetaConfig, Profile};
+use crate::error::ApiError;
+use crate::report::Timing;
+use crate::version::{ClientVersions, CLIENT_VERSION, KEYLOG_FORMAT};
+use crate::vim::VimInfo;
 
 const VIMRC_CONTENTS: &str = include_str!("vimrc");
+const MATCH_INDICATOR_CONTENTS: &str = include_str!("match_indicator.vim");
+const SESSION_TIMER_CONTENTS: &str = include_str!("session_timer.vim");
+
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_AFTER_SECS: u64 = 60;
 
 #[cfg(windows)]
 const EOL: &str = "\r\n";
@@ -18,80 +38,206 @@ const EOL: &str = "\n";
 
 pub struct Controller {
     host: Url,
-    tempdir: TempDir,
+    language: Language,
+    meta: MetaConfig,
+    http: HttpConfig,
+    client: Client,
+    user_token: Option<String>,
+    // Removed along with its contents when the controller is dropped, unless the session is kept
+    tempdir: Option<TempDir>,
+    dir: PathBuf,
     vimrc_revision_id: Option<u32>,
+    // Whether the server expects the token the old way, once it's known
+    legacy_server: OnceCell<bool>,
 }
 
 impl Controller {
-    pub fn new(host: Url) -> ::anyhow::Result<Self> {
+    pub fn new(profile: &Profile, config: &Config) -> ::anyhow::Result<Self> {
         let tempdir = TempDir::new()?;
+        debug!(path = %tempdir.path().display(), "created temp dir");
 
         fs::write(tempdir.path().join("vimrc"), VIMRC_CONTENTS)?;
 
-        Ok(Self { host, tempdir, vimrc_revision_id: None })
+        Ok(Self {
+            host: profile.host.clone(),
+            language: config.language,
+            meta: config.meta.clone(),
+            http: config.http.clone(),
+            client: build_client(config.language, &config.http, None)?,
+            user_token: None,
+            dir: tempdir.path().to_path_buf(),
+            tempdir: Some(tempdir),
+            vimrc_revision_id: None,
+            legacy_server: OnceCell::new(),
+        })
+    }
+
+    /// Every request after this one sends the token in an `Authorization: Bearer` header. Only
+    /// over https, apart from a server on this machine, since anyone on the way could read it.
+    ///
+    pub fn authenticate(&mut self, user_token: &str) -> ::anyhow::Result<()> {
+        if self.host.scheme() != "https" && !is_loopback(&self.host) {
+            return Err(anyhow!(
+                "Сайтът {} не използва https, токенът няма да бъде изпратен по нешифрована връзка",
+                self.host,
+            ));
+        }
+
+        self.client = build_client(self.language, &self.http, Some(user_token))?;
+        self.user_token = Some(user_token.to_owned());
+        Ok(())
     }
 
     pub fn vimrc_path(&self) -> PathBuf {
-        self.tempdir.path().join("vimrc")
+        self.dir.join("vimrc")
     }
 
-    pub fn setup_user(&self, user_token: &str) -> ::anyhow::Result<User> {
+    /// An empty home directory for Vim, so it can't find the user's own config.
+    pub fn home_path(&self) -> ::anyhow::Result<PathBuf> {
+        let path = self.dir.join("home");
+        fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    /// Makes sure the session's files stay around after it's done, either where they are or in
+    /// the given directory, which is created if it doesn't exist. Files that are already there are
+    /// moved along. The directory has to be empty, so an earlier kept session isn't overwritten.
+    ///
+    pub fn keep_session(&mut self, target: Option<&Path>) -> ::anyhow::Result<PathBuf> {
+        if let Some(target) = target {
+            fs::create_dir_all(target)?;
+            if fs::read_dir(target)?.next().is_some() {
+                return Err(anyhow!("Директорията {} не е празна, избери друга", target.display()));
+            }
+
+            copy_dir(&self.dir, target)?;
+            self.dir = target.canonicalize()?;
+            // The old directory's contents have all been copied, so it can go
+            self.tempdir = None;
+        } else if let Some(tempdir) = self.tempdir.take() {
+            self.dir = tempdir.into_path();
+        }
+
+        debug!(path = %self.dir.display(), "keeping session dir");
+        Ok(self.dir.clone())
+    }
+
+    pub fn setup_user(&mut self, user_token: &str) -> ::anyhow::Result<User> {
+        self.authenticate(user_token)?;
+
         let endpoint = self.host.join("/api/setup.json")?;
-        let client = reqwest::blocking::Client::new();
-        let meta = get_meta(None, None);
+        let meta = get_meta(&self.meta, None, None);
 
-        let body = serde_urlencoded::to_string([
-            ("token", user_token.to_owned()),
-            ("meta", meta.to_string()),
-        ])?;
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+        let mut params = vec![("meta", meta.to_string())];
+        let mut response = self.send(self.client.post(endpoint.clone()).
+            header(IDEMPOTENCY_KEY, &idempotency_key).
+            body(serde_urlencoded::to_string(&params)?))?;
 
-        let response = client.post(endpoint).body(body).send()?;
+        if is_legacy_auth_failure(&response) && self.uses_legacy_auth() {
+            params.push(("token", user_token.to_owned()));
+            response = self.send(self.client.post(endpoint).
+                header(IDEMPOTENCY_KEY, &idempotency_key).
+                body(serde_urlencoded::to_string(&params)?))?;
+        }
 
         if response.status() == 200 {
-            let user = response.json()?;
-            write_user(&user)?;
+            let user = parse_json(response)?;
             Ok(user)
         } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
+            Err(ApiError::from_response(response).into())
         }
     }
 
+    pub fn validate_user(&self) -> ::anyhow::Result<User> {
+        let endpoint = self.host.join("/api/user.json")?;
+        let response = self.send(self.client.get(endpoint))?;
+
+        if response.status() == 200 {
+            let user = parse_json(response)?;
+            Ok(user)
+        } else {
+            Err(ApiError::from_response(response).into())
+        }
+    }
+
+    /// Whether a rejected request should be sent again with the token in the URL or the body.
+    /// Only for servers that ask for it in `/api/client_version.json` or are too old to have it,
+    /// or with `http.legacy_auth`, since anything else that responds with a 404 shouldn't get the
+    /// token. The server is only asked once.
+    ///
+    fn uses_legacy_auth(&self) -> bool {
+        if self.http.legacy_auth {
+            return true;
+        }
+        if let Some(&legacy_server) = self.legacy_server.get() {
+            return legacy_server;
+        }
+
+        // Remembers the answer along the way
+        self.client_versions().map(|versions| versions.legacy_auth).unwrap_or(false)
+    }
+
+    pub fn client_versions(&self) -> ::anyhow::Result<ClientVersions> {
+        let endpoint = self.host.join("/api/client_version.json")?;
+        let response = self.send(self.client.get(endpoint))?;
+
+        let versions =
+            if response.status() == 200 {
+                parse_json(response)?
+            } else if response.status() == StatusCode::NOT_FOUND {
+                // Older servers don't have this endpoint, and they're the ones that expect the
+                // token the old way
+                ClientVersions { legacy_auth: true, ..ClientVersions::default() }
+            } else {
+                return Err(ApiError::from_response(response).into());
+            };
+
+        let _ = self.legacy_server.set(versions.legacy_auth);
+        Ok(versions)
+    }
+
     pub fn download_task(&self, task_id: &str) -> ::anyhow::Result<Task> {
         let path = format!("/api/task/{}.json", task_id);
         let endpoint = self.host.join(&path)?;
-        let response = reqwest::blocking::get(endpoint)?;
+        let response = self.send(self.client.get(endpoint))?;
 
         if response.status() == 200 {
-            let exercise = response.json()?;
+            let exercise = parse_json(response)?;
             Ok(exercise)
         } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
+            Err(ApiError::from_response(response).into())
         }
     }
 
     pub fn download_free_task(&self, free_task_id: &str) -> ::anyhow::Result<Task> {
         let path = format!("/api/free_task/{}.json", free_task_id);
         let endpoint = self.host.join(&path)?;
-        let response = reqwest::blocking::get(endpoint)?;
+        let response = self.send(self.client.get(endpoint))?;
 
         if response.status() == 200 {
-            let exercise = response.json()?;
+            let exercise = parse_json(response)?;
             Ok(exercise)
         } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
+            Err(ApiError::from_response(response).into())
         }
     }
 
-    pub fn download_vimrc(&mut self, user_token: &str) -> ::anyhow::Result<()> {
-        let path = format!("/api/vimrc/{}.json", user_token);
-        let endpoint = self.host.join(&path)?;
-        let response = reqwest::blocking::get(endpoint)?;
+    pub fn download_vimrc(&mut self) -> ::anyhow::Result<()> {
+        let user_token = self.user_token.clone().
+            ok_or_else(|| anyhow!("Can't download a vimrc without a user"))?;
+
+        let endpoint = self.host.join("/api/vimrc.json")?;
+        let mut response = self.send(self.client.get(endpoint))?;
+
+        if is_legacy_auth_failure(&response) && self.uses_legacy_auth() {
+            let path = format!("/api/vimrc/{}.json", user_token);
+            response = self.send(self.client.get(self.host.join(&path)?))?;
+        }
 
         if response.status() == 200 {
-            let vimrc: Vimrc = response.json()?;
+            let vimrc: Vimrc = parse_json(response)?;
             self.vimrc_revision_id = vimrc.revision_id;
 
             // We print line by line to make sure we've got the right EOLs
@@ -105,13 +251,60 @@ impl Controller {
                 }
             }
 
-            // TODO: Debug mode
-            // println!("{}", fs::read_to_string(self.vimrc_path()).unwrap());
+            debug!(revision_id = ?self.vimrc_revision_id, "downloaded vimrc");
 
             Ok(())
         } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
+            Err(ApiError::from_response(response).into())
+        }
+    }
+
+    // Retries requests that fail because of the network or a 429/5xx response, with exponential
+    // backoff or as long as the server says in `Retry-After`. Only meant for GET requests and POST
+    // requests with an idempotency key.
+    fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        let mut attempt = 0;
+
+        loop {
+            // Unwrap: none of the requests have streaming bodies
+            let current_request = request.try_clone().unwrap().build()?;
+            let can_retry = attempt < self.http.retries;
+
+            let method = current_request.method().clone();
+            let url = self.redact(current_request.url());
+            debug!(%method, %url, attempt, "request");
+
+            match self.client.execute(current_request) {
+                Ok(response) if can_retry && is_retryable_status(response.status()) => {
+                    let wait = retry_after(&response).unwrap_or_else(|| backoff(attempt));
+                    debug!(%method, %url, status = %response.status(), ?wait, "retrying");
+                    thread::sleep(wait);
+                },
+                Ok(response) => {
+                    debug!(%method, %url, status = %response.status(), "response");
+                    return Ok(response);
+                },
+                Err(e) if can_retry && (e.is_timeout() || e.is_connect()) => {
+                    let wait = backoff(attempt);
+                    debug!(%method, %url, error = %e, ?wait, "retrying");
+                    thread::sleep(wait);
+                },
+                Err(e) => {
+                    debug!(%method, %url, error = %e, "request failed");
+                    return Err(e.into());
+                },
+            }
+
+            attempt += 1;
+        }
+    }
+
+    // Legacy endpoints have the token in the URL, and it shouldn't end up in logs people paste in
+    // Discord.
+    fn redact(&self, url: &Url) -> String {
+        match &self.user_token {
+            Some(user_token) => url.as_str().replace(user_token.as_str(), "[token]"),
+            None => url.to_string(),
         }
     }
 
@@ -119,116 +312,266 @@ impl Controller {
         &self,
         task_id: &str,
         bytes: Vec<u8>,
-        vim_executable: &str,
-        elapsed_time: u128,
-    ) -> ::anyhow::Result<bool> {
-        let endpoint = self.host.join("/api/solution.json")?;
-        let client = reqwest::blocking::Client::new();
-        let meta = get_meta(Some(vim_executable), Some(elapsed_time));
-        // Unwrap: We should have checked for a user before
-        let user = read_user()?.unwrap();
-
-        let vimrc_revision_id = self.vimrc_revision_id.
-            map(|id| id.to_string()).
-            unwrap_or_else(String::new);
-
-        let body = serde_urlencoded::to_string([
-            ("entry", ::base64::engine::general_purpose::STANDARD.encode(bytes)),
-            ("challenge_id", task_id.to_owned()),
-            ("vimrc_revision_id", vimrc_revision_id),
-            ("user_token", user.token),
-            ("meta", meta.to_string()),
-        ])?;
-        let response = client.post(endpoint).body(body).send()?;
-
-        if response.status().is_success() {
-            Ok(true)
-        } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
-        }
+        vim: &VimMeta,
+        timing: &Timing,
+    ) -> ::anyhow::Result<UploadResponse> {
+        self.upload_entry("/api/solution.json", task_id, bytes, vim, timing)
     }
 
     pub fn upload_free_task(
         &self,
         free_task_id: &str,
         bytes: Vec<u8>,
-        vim_executable: &str,
-        elapsed_time: u128,
-    ) -> ::anyhow::Result<bool> {
-        let endpoint = self.host.join("/api/free_task_solution.json")?;
-        let client = reqwest::blocking::Client::new();
-        let meta = get_meta(Some(vim_executable), Some(elapsed_time));
-        // Unwrap: We should have checked for a user before
-        let user = read_user()?.unwrap();
+        vim: &VimMeta,
+        timing: &Timing,
+    ) -> ::anyhow::Result<UploadResponse> {
+        self.upload_entry("/api/free_task_solution.json", free_task_id, bytes, vim, timing)
+    }
+
+    fn upload_entry(
+        &self,
+        path: &str,
+        challenge_id: &str,
+        bytes: Vec<u8>,
+        vim: &VimMeta,
+        timing: &Timing,
+    ) -> ::anyhow::Result<UploadResponse> {
+        let user_token = self.user_token.clone().
+            ok_or_else(|| anyhow!("Can't upload a solution without a user"))?;
+
+        let endpoint = self.host.join(path)?;
+        let meta = get_meta(&self.meta, Some(vim), Some(timing));
 
         let vimrc_revision_id = self.vimrc_revision_id.
             map(|id| id.to_string()).
             unwrap_or_else(String::new);
 
-        let body = serde_urlencoded::to_string([
+        let mut params = vec![
             ("entry", ::base64::engine::general_purpose::STANDARD.encode(bytes)),
-            ("challenge_id", free_task_id.to_owned()),
+            ("challenge_id", challenge_id.to_owned()),
             ("vimrc_revision_id", vimrc_revision_id),
-            ("user_token", user.token),
             ("meta", meta.to_string()),
-        ])?;
-        let response = client.post(endpoint).body(body).send()?;
+        ];
+        // The server only accepts a solution once per key, so retries can't submit it twice
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+        let mut response = self.send(self.client.post(endpoint.clone()).
+            header(IDEMPOTENCY_KEY, &idempotency_key).
+            body(serde_urlencoded::to_string(&params)?))?;
+
+        if is_legacy_auth_failure(&response) && self.uses_legacy_auth() {
+            params.push(("user_token", user_token));
+            response = self.send(self.client.post(endpoint).
+                header(IDEMPOTENCY_KEY, &idempotency_key).
+                body(serde_urlencoded::to_string(&params)?))?;
+        }
 
         if response.status().is_success() {
-            Ok(true)
+            // Older servers respond with an empty body
+            let body = response.text().map_err(ApiError::from)?;
+            Ok(serde_json::from_str(&body).unwrap_or_default())
         } else {
-            let error: JsonError = response.json()?;
-            Err(anyhow!("{}", error.message))
+            Err(ApiError::from_response(response).into())
         }
     }
 
+    // Should be called after `download_vimrc`, so the indicator ends up in the user's statusline
+    // as well.
+    pub fn enable_match_indicator(&self, expected_output: &str) -> ::anyhow::Result<()> {
+        self.create_file("expected", expected_output)?;
+        self.create_file("match_indicator.vim", MATCH_INDICATOR_CONTENTS)?;
+
+        let mut file = File::options().append(true).open(self.vimrc_path())?;
+        write!(file, "{}", EOL)?;
+        write!(file, "execute 'source' fnameescape(expand('<sfile>:p:h') . '/match_indicator.vim'){}", EOL)?;
+
+        Ok(())
+    }
+
+    /// Counts idle time in the session, and with a `time_limit` in seconds, closes Vim when it's
+    /// up. Like the match indicator, it's loaded after the user's vimrc.
+    ///
+    pub fn enable_session_timer(&self, time_limit: Option<u64>) -> ::anyhow::Result<()> {
+        self.create_file("time_limit", &time_limit.unwrap_or(0).to_string())?;
+        self.create_file("session_timer.vim", SESSION_TIMER_CONTENTS)?;
+
+        let mut file = File::options().append(true).open(self.vimrc_path())?;
+        write!(file, "{}", EOL)?;
+        write!(file, "execute 'source' fnameescape(expand('<sfile>:p:h') . '/session_timer.vim'){}", EOL)?;
+
+        Ok(())
+    }
+
+    /// The idle time counted by the session timer, in milliseconds. Missing if the Vim doesn't
+    /// have timers or was killed.
+    ///
+    pub fn idle_time(&self) -> Option<u128> {
+        let contents = fs::read_to_string(self.dir.join("idle")).ok()?;
+        let seconds: u128 = contents.trim().parse().ok()?;
+        Some(seconds * 1000)
+    }
+
+    /// Whether the session timer closed Vim because the time ran out.
+    pub fn timed_out(&self) -> bool {
+        self.dir.join("timed_out").exists()
+    }
+
     pub fn create_file(&self, name: &str, contents: &str) -> ::anyhow::Result<PathBuf> {
-        let path = self.tempdir.path().join(name);
+        let path = self.dir.join(name);
         fs::write(&path, contents)?;
+        debug!(path = %path.display(), "created file");
         Ok(path)
     }
 }
 
-fn get_meta(vim_executable: Option<&str>, elapsed_time: Option<u128>) -> serde_json::Value {
-    serde_json::json!({
-        "username": ::whoami::username(),
-        "devicename": ::whoami::devicename(),
-        "platform": ::whoami::platform().to_string(),
-        "client_version": ::clap::crate_version!(),
-        "vim_executable": vim_executable,
-        "time": elapsed_time,
-    })
+// With subdirectories, like Vim's home
+fn copy_dir(from: &Path, to: &Path) -> ::anyhow::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
 }
 
-pub fn read_user() -> ::anyhow::Result<Option<User>> {
-    let proj_dirs = ProjectDirs::from("bg", "fmi", "vim-fmi-cli").
-        ok_or_else(|| anyhow!("Couldn't initialize project dir"))?;
-    let path = proj_dirs.data_dir().join("user.json");
-    if !path.exists() {
-        return Ok(None);
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
     }
+}
 
-    let data_file = File::open(path)?;
-    let user = serde_json::from_reader(data_file)?;
+pub(crate) fn build_client(
+    language: Language,
+    http: &HttpConfig,
+    user_token: Option<&str>,
+) -> ::anyhow::Result<Client> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(language.code()));
+
+    if let Some(user_token) = user_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", user_token))?;
+        value.set_sensitive(true);
+        headers.insert(header::AUTHORIZATION, value);
+    }
+
+    let mut builder = Client::builder().
+        default_headers(headers).
+        user_agent(format!("vim-fmi/{}", CLIENT_VERSION)).
+        connect_timeout(Duration::from_secs(http.connect_timeout)).
+        timeout(Duration::from_secs(http.timeout));
+
+    // Setting a proxy explicitly turns off the ones from the environment
+    if let Some(proxy_url) = &http.proxy {
+        let no_proxy = match &http.no_proxy {
+            Some(no_proxy) => NoProxy::from_string(no_proxy),
+            None => NoProxy::from_env(),
+        };
+        builder = builder.proxy(Proxy::all(proxy_url.clone())?.no_proxy(no_proxy));
+    }
+
+    if let Some(ca_cert) = &http.ca_cert {
+        let pem = fs::read(ca_cert).
+            map_err(|e| anyhow!("Не може да бъде прочетен сертификатът {}: {}", ca_cert.display(), e))?;
+        // A file without certificates would otherwise be quietly ignored
+        if !String::from_utf8_lossy(&pem).contains("-----BEGIN CERTIFICATE-----") {
+            return Err(anyhow!("Няма сертификати в {}", ca_cert.display()));
+        }
+        let certificate = Certificate::from_pem(&pem).
+            map_err(|e| anyhow!("Невалиден сертификат {}: {}", ca_cert.display(), e))?;
+
+        builder = builder.add_root_certificate(certificate);
+    }
 
-    Ok(Some(user))
+    Ok(builder.build()?)
 }
 
-fn write_user(user: &User) -> ::anyhow::Result<()> {
-    let proj_dirs = ProjectDirs::from("bg", "fmi", "vim-fmi-cli").
-        ok_or_else(|| anyhow!("Couldn't initialize project dir"))?;
-    let data_dir = proj_dirs.data_dir();
+fn parse_json<T: DeserializeOwned>(response: Response) -> Result<T, ApiError> {
+    let body = response.text()?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
 
-    fs::create_dir_all(data_dir)?;
-    fs::write(data_dir.join("user.json"), serde_json::to_string(user)?)?;
+// Only the number of seconds is supported, not an HTTP date. Long waits are capped, it's better to
+// fail and let the user try again.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds: u64 = response.headers().
+        get(header::RETRY_AFTER)?.
+        to_str().ok()?.
+        trim().parse().ok()?;
 
-    Ok(())
+    Some(Duration::from_secs(seconds.min(MAX_RETRY_AFTER_SECS)))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct JsonError {
-    pub message: String,
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF * 2_u32.pow(attempt.min(6))
+}
+
+// Older servers don't look at the `Authorization` header. They expect the token in the URL or the
+// request body and respond with one of these if it's not there. Only checked for servers that
+// use the old way, see `Controller::uses_legacy_auth`.
+fn is_legacy_auth_failure(response: &Response) -> bool {
+    matches!(
+        response.status(),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+    )
+}
+
+fn get_meta(
+    config: &MetaConfig,
+    vim: Option<&VimMeta>,
+    timing: Option<&Timing>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "username": config.username.then(::whoami::username),
+        "devicename": config.devicename.then(::whoami::devicename),
+        "platform": config.platform.then(|| ::whoami::platform().to_string()),
+        "client_version": CLIENT_VERSION,
+        "keylog_format": KEYLOG_FORMAT,
+        "vim_executable": vim.map(|vim| vim.executable),
+        "vim": vim.and_then(|vim| vim.info),
+        "time": timing.map(|timing| timing.elapsed_time),
+        "idle_time": timing.and_then(|timing| timing.idle_time),
+        "timed_out": timing.map(|timing| timing.timed_out),
+    })
+}
+
+/// The Vim a solution was made with, sent along with it.
+#[derive(Debug, Clone, Copy)]
+pub struct VimMeta<'a> {
+    pub executable: &'a str,
+    pub info: Option<&'a VimInfo>,
+}
+
+/// What the server says about an uploaded solution. Older servers don't send anything, so every
+/// field is optional.
+///
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UploadResponse {
+    /// Reported on its own, as the `message` of the task report
+    #[serde(skip_serializing)]
+    pub message: Option<String>,
+    /// The number of keys, as counted by the server
+    pub score: Option<u32>,
+    /// The user's best score for the task, including this one
+    pub best_score: Option<u32>,
+    /// The user's place in the task's ranking
+    pub rank: Option<u32>,
+    /// Whether this solution is better than the user's previous best
+    pub improved: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -237,6 +580,8 @@ pub struct Task {
     pub output: String,
     pub version: String,
     pub file_extension: Option<String>,
+    /// In seconds, for timed tasks
+    pub time_limit: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -251,3 +596,82 @@ pub struct Vimrc {
     pub revision_id: Option<u32>,
     pub body: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> (TempDir, Controller) {
+        controller_for("http://localhost:3000")
+    }
+
+    fn controller_for(host: &str) -> (TempDir, Controller) {
+        let data_dir = TempDir::new().unwrap();
+        let profile = Profile {
+            name: String::from("test"),
+            host: Url::parse(host).unwrap(),
+            data_dir: data_dir.path().to_path_buf(),
+        };
+
+        (data_dir, Controller::new(&profile, &Config::default()).unwrap())
+    }
+
+    #[test]
+    fn token_only_over_https_or_locally() {
+        for host in ["https://vim-fmi.bg", "http://localhost:3000", "http://127.0.0.1:3000", "http://[::1]"] {
+            let (_data_dir, mut controller) = controller_for(host);
+            assert!(controller.authenticate("token").is_ok(), "{} was refused", host);
+        }
+
+        let (_data_dir, mut controller) = controller_for("http://vim-fmi.bg");
+        assert!(controller.authenticate("token").is_err());
+        assert!(controller.user_token.is_none());
+    }
+
+    #[test]
+    fn idle_time_in_milliseconds() {
+        let (_data_dir, controller) = controller();
+
+        controller.create_file("idle", "42\n").unwrap();
+        assert_eq!(controller.idle_time(), Some(42_000));
+
+        controller.create_file("idle", "0").unwrap();
+        assert_eq!(controller.idle_time(), Some(0));
+    }
+
+    #[test]
+    fn idle_time_missing_or_broken() {
+        let (_data_dir, controller) = controller();
+        assert_eq!(controller.idle_time(), None);
+
+        controller.create_file("idle", "").unwrap();
+        assert_eq!(controller.idle_time(), None);
+
+        controller.create_file("idle", "-3").unwrap();
+        assert_eq!(controller.idle_time(), None);
+    }
+
+    #[test]
+    fn keep_session_with_subdirectories() {
+        let (data_dir, mut controller) = controller();
+        let home = controller.home_path().unwrap();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        fs::write(home.join(".config/file"), "contents").unwrap();
+
+        let target = data_dir.path().join("kept");
+        let kept = controller.keep_session(Some(&target)).unwrap();
+
+        assert_eq!(fs::read_to_string(kept.join("home/.config/file")).unwrap(), "contents");
+        assert!(kept.join("vimrc").exists());
+        assert_eq!(controller.home_path().unwrap(), kept.join("home"));
+    }
+
+    #[test]
+    fn timed_out_marker() {
+        let (_data_dir, controller) = controller();
+        assert!(!controller.timed_out());
+
+        controller.create_file("timed_out", "1\n").unwrap();
+        assert!(controller.timed_out());
+    }
+}
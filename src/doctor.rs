@@ -0,0 +1,272 @@
+use std::fs;
+
+use serde::Serialize;
+
+use crate::accounts::Accounts;
+use crate::config::{Config, Profile};
+use crate::controller::Controller;
+use crate::error::ApiError;
+use crate::report::Keys;
+use crate::version::VersionStatus;
+use crate::vim::{Vim, VimFlavor};
+
+// The vimgolf vimrc and the match indicator can't work without these
+const REQUIRED_FEATURES: &[&str] = &["eval", "autocmd", "statusline"];
+
+const KEYLOG_SCRIPT: &str = r"ihello\<Esc>:wq\<CR>";
+const KEYLOG_EXPECTED: &[&str] = &["i", "h", "e", "l", "l", "o", "<Esc>", ":", "w", "q", "<CR>"];
+
+// Keys that are logged as multibyte codes, most of them from the KS_EXTRA range that shifts
+// between Vim versions. <F1> and <Insert> are left out, since they'd open the help or start insert
+// mode in the middle of the session.
+const SPECIAL_KEYS: &[&str] = &[
+    "<Up>", "<Down>", "<Left>", "<Right>",
+    "<Home>", "<End>", "<PageUp>", "<PageDown>", "<Del>", "<BS>",
+    "<F2>", "<F3>", "<F4>", "<F5>", "<F6>", "<F7>", "<F8>", "<F9>", "<F10>", "<F11>", "<F12>",
+    "<S-Up>", "<S-Down>", "<S-Left>", "<S-Right>", "<S-Home>", "<S-End>", "<S-Tab>",
+    "<C-Left>", "<C-Right>", "<C-Home>", "<C-End>",
+    "<kHome>", "<kEnd>", "<kPageUp>", "<kPageDown>",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ok,
+    /// Things work, but not as well as they could
+    Warning,
+    /// Solving tasks won't work until this is fixed
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub details: String,
+    /// What the user could do about it, if it's not ok
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, details: impl Into<String>) -> Self {
+        Self { name, status: Status::Ok, details: details.into(), fix: None }
+    }
+
+    fn warning(name: &'static str, details: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { name, status: Status::Warning, details: details.into(), fix: Some(fix.into()) }
+    }
+
+    fn error(name: &'static str, details: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { name, status: Status::Error, details: details.into(), fix: Some(fix.into()) }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyCheck {
+    pub expected: &'static str,
+    pub recorded: String,
+    pub ok: bool,
+}
+
+/// Types each of the special keys in a scripted session and checks that the keylog decodes back to
+/// the same key. Each key is followed by an `<Esc>`, so a key that's decoded as several (or none)
+/// doesn't throw off the ones after it.
+///
+pub fn check_special_keys(vim: &Vim, controller: &Controller) -> ::anyhow::Result<Vec<KeyCheck>> {
+    let input_path = controller.create_file("check-keys.txt", "")?;
+    let log_path = controller.create_file("check-keys.log", "")?;
+
+    let mut script: String = SPECIAL_KEYS.iter().map(|key| format!("\\{}\\<Esc>", key)).collect();
+    script.push_str(r":qa!\<CR>");
+
+    let (_, log_bytes) = vim.run_scripted(&input_path, &log_path, &script)?;
+    let keys = Keys::from_log(&log_bytes).keys;
+    let mut recorded = keys.split(|key| key == "<Esc>");
+
+    let checks = SPECIAL_KEYS.iter().map(|expected| {
+        let recorded = recorded.next().map(|keys| keys.concat()).unwrap_or_default();
+        KeyCheck { expected, ok: recorded == *expected, recorded }
+    }).collect();
+
+    Ok(checks)
+}
+
+/// Checks everything that usually goes wrong on a student's machine: the Vim that would be used,
+/// whether its keylog can be read, the connection to the server, the stored token and the data
+/// directory.
+///
+pub fn run_checks(
+    profile: &Profile,
+    config: &Config,
+    accounts: &Accounts,
+    user: Option<&str>,
+) -> ::anyhow::Result<Vec<Check>> {
+    let mut controller = Controller::new(profile, config)?;
+    let mut checks = Vec::new();
+
+    match Vim::new(controller.vimrc_path(), controller.home_path()?, &config.vim) {
+        Ok(vim) => check_vim(&vim, &controller, &mut checks),
+        Err(e) => checks.push(Check::error(
+            "Vim",
+            format!("{:#}", e),
+            "Инсталирай Vim или задай пътя до него с `vim-fmi config set vim.executable <път>`, или провери `vim.preference` и `vim.interface`",
+        )),
+    }
+
+    checks.push(check_server(&controller));
+
+    match accounts.read(user) {
+        Ok(Some(user)) => {
+            controller.authenticate(&user.token)?;
+            checks.push(check_token(&controller));
+        },
+        Ok(None) => checks.push(Check::warning(
+            "Токен",
+            "Няма активиран акаунт на този компютър",
+            "Създай си token в сайта и извикай `vim-fmi setup <token>`",
+        )),
+        Err(e) => checks.push(Check::error(
+            "Токен",
+            format!("{:#}", e),
+            "Изтрий акаунта с `vim-fmi logout` и го активирай отново с `vim-fmi setup <token>`",
+        )),
+    }
+
+    checks.push(check_data_dir(profile));
+
+    Ok(checks)
+}
+
+fn check_vim(vim: &Vim, controller: &Controller, checks: &mut Vec<Check>) {
+    let info = match &vim.info {
+        Some(info) => info,
+        None => {
+            checks.push(Check::ok("Vim", format!("{} (версията не се проверява под Windows)", vim.executable)));
+            checks.push(check_keylog(vim, controller));
+            return;
+        },
+    };
+
+    checks.push(Check::ok("Vim", format!("{} ({})", vim.executable, info)));
+
+    // Neovim doesn't list features, it has all of these anyway
+    if info.flavor == VimFlavor::Vim {
+        let missing: Vec<String> = REQUIRED_FEATURES.iter().
+            filter(|feature| !info.has(feature)).
+            map(|feature| format!("+{}", feature)).
+            collect();
+
+        if missing.is_empty() {
+            let features: Vec<String> = REQUIRED_FEATURES.iter().map(|f| format!("+{}", f)).collect();
+            checks.push(Check::ok("Функции на Vim", features.join(" ")));
+        } else {
+            checks.push(Check::warning(
+                "Функции на Vim",
+                format!("Липсват {}", missing.join(" ")),
+                "Инсталирай по-пълна версия на Vim, например `vim-nox` или `vim-gtk3` вместо `vim-tiny`",
+            ));
+        }
+    }
+
+    checks.push(check_keylog(vim, controller));
+}
+
+fn check_keylog(vim: &Vim, controller: &Controller) -> Check {
+    const NAME: &str = "Запис на клавиши";
+    const FIX: &str = "Пробвай с друга версия на Vim: `vim-fmi config set vim.executable <път>`";
+
+    let paths = controller.create_file("doctor.txt", "").
+        and_then(|input| Ok((input, controller.create_file("doctor.log", "")?)));
+    let (input_path, log_path) = match paths {
+        Ok(paths) => paths,
+        Err(e) => return Check::error(NAME, format!("{:#}", e), FIX),
+    };
+
+    let (output, log_bytes) = match vim.run_scripted(&input_path, &log_path, KEYLOG_SCRIPT) {
+        Ok(result) => result,
+        Err(e) => return Check::error(NAME, format!("Vim не успя да изпълни тестовата сесия: {:#}", e), FIX),
+    };
+
+    let keys = Keys::from_log(&log_bytes);
+    if keys.keys != KEYLOG_EXPECTED {
+        return Check::error(
+            NAME,
+            format!("Очаквани клавиши {}, записани {}", KEYLOG_EXPECTED.concat(), keys.script),
+            FIX,
+        );
+    }
+    if output.trim() != "hello" {
+        return Check::warning(
+            NAME,
+            format!("Клавишите са записани, но файлът съдържа {:?} вместо \"hello\"", output.trim()),
+            "Провери дали vimrc-то ти в сайта не променя въвеждането на текст",
+        );
+    }
+
+    Check::ok(NAME, keys.script)
+}
+
+fn check_server(controller: &Controller) -> Check {
+    const NAME: &str = "Сайт";
+
+    match controller.client_versions() {
+        Ok(versions) => match versions.status() {
+            VersionStatus::UpToDate => Check::ok(NAME, "Сайтът отговаря, версията на клиента е последната"),
+            VersionStatus::Outdated { latest } => Check::warning(
+                NAME,
+                format!("Има нова версия на клиента ({})", latest),
+                "Обнови клиента с `vim-fmi self-update`",
+            ),
+            VersionStatus::Unsupported { reason } => Check::error(
+                NAME,
+                format!("Тази версия на клиента вече не се поддържа: {}", reason),
+                "Обнови клиента с `vim-fmi self-update`",
+            ),
+        },
+        Err(e) => api_error_check(NAME, e),
+    }
+}
+
+fn check_token(controller: &Controller) -> Check {
+    const NAME: &str = "Токен";
+
+    match controller.validate_user() {
+        Ok(user) => Check::ok(NAME, format!("Валиден, факултетен номер: {}", user.faculty_number)),
+        Err(e) => match e.downcast_ref::<ApiError>() {
+            Some(api_error @ (ApiError::Auth(_) | ApiError::NotFound(_))) => Check::error(
+                NAME,
+                api_error.to_string(),
+                "Създай си нов token в сайта и извикай `vim-fmi setup <token>`",
+            ),
+            _ => api_error_check(NAME, e),
+        },
+    }
+}
+
+fn check_data_dir(profile: &Profile) -> Check {
+    const NAME: &str = "Директория с данни";
+
+    let writable = fs::create_dir_all(&profile.data_dir).
+        and_then(|_| tempfile::NamedTempFile::new_in(&profile.data_dir));
+
+    match writable {
+        Ok(_) => Check::ok(NAME, profile.data_dir.display().to_string()),
+        Err(e) => Check::error(
+            NAME,
+            format!("{} не може да се пише: {}", profile.data_dir.display(), e),
+            format!("Провери правата на {}", profile.data_dir.display()),
+        ),
+    }
+}
+
+fn api_error_check(name: &'static str, error: ::anyhow::Error) -> Check {
+    match error.downcast_ref::<ApiError>() {
+        // The message of an `ApiError` already includes its cause
+        Some(api_error) => Check::error(
+            name,
+            api_error.to_string(),
+            api_error.hint().unwrap_or("Пробвай пак след малко."),
+        ),
+        None => Check::error(name, format!("{:#}", error), "Пробвай пак след малко."),
+    }
+}
@@ -0,0 +1,100 @@
+use reqwest::StatusCode;
+use reqwest::blocking::Response;
+use serde::Deserialize;
+
+/// Everything that can go wrong when talking to the server.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("Няма връзка със сайта: {0}")]
+    Network(#[source] reqwest::Error),
+
+    #[error("Сайтът не отговори навреме")]
+    Timeout,
+
+    #[error("Сайтът не прие токена: {0}")]
+    Auth(String),
+
+    #[error("Не е намерено: {0}")]
+    NotFound(String),
+
+    #[error("Сайтът върна грешка {status}: {message}")]
+    Server { status: u16, message: String },
+
+    #[error("Неочакван отговор от сайта: {0}")]
+    MalformedResponse(String),
+
+    #[error("Тази версия на клиента вече не се поддържа: {0}")]
+    VersionMismatch(String),
+}
+
+impl ApiError {
+    /// Builds an error out of a response with an unsuccessful status. The body is expected to be
+    /// JSON with a `message`, but it might just as well be an HTML error page or nothing at all.
+    ///
+    pub fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let body = response.text().unwrap_or_default();
+
+        let message = serde_json::from_str::<JsonError>(&body).
+            map(|error| error.message).
+            unwrap_or_else(|_| {
+                status.canonical_reason().unwrap_or("неизвестна грешка").to_owned()
+            });
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ApiError::Auth(message),
+            StatusCode::NOT_FOUND => ApiError::NotFound(message),
+            StatusCode::UPGRADE_REQUIRED => ApiError::VersionMismatch(message),
+            _ => ApiError::Server { status: status.as_u16(), message },
+        }
+    }
+
+    /// Something the user could do about the error, if there is anything.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ApiError::Network(_) | ApiError::Timeout => {
+                Some("Провери интернет връзката си и пробвай пак.")
+            },
+            ApiError::Auth(_) => {
+                Some("Създай си нов token в сайта и извикай `vim-fmi setup <token>`.")
+            },
+            ApiError::NotFound(_) => {
+                Some("Провери дали идентификаторът на упражнението е правилен.")
+            },
+            ApiError::Server { .. } | ApiError::MalformedResponse(_) => {
+                Some("Пробвай пак след малко. Ако не проработи, пиши в Discord или по мейл.")
+            },
+            ApiError::VersionMismatch(_) => {
+                Some("Обнови клиента с `vim-fmi self-update` или го свали от https://github.com/AndrewRadev/vim-fmi-cli/releases")
+            },
+        }
+    }
+}
+
+/// Added as context to errors while uploading a solution that was otherwise correct.
+#[derive(Debug, thiserror::Error)]
+#[error("Решението е вярно, но не можа да бъде качено")]
+pub struct UploadFailed;
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ApiError::Timeout
+        } else if error.is_decode() {
+            ApiError::MalformedResponse(error.to_string())
+        } else {
+            ApiError::Network(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        ApiError::MalformedResponse(error.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonError {
+    pub message: String,
+}
@@ -0,0 +1,60 @@
+//! Exit codes of the `vim-fmi` executable. These are stable, so scripts and editor integrations can
+//! rely on them. `TABLE` describes each one, and it's shown at the end of `vim-fmi --help`.
+
+use crate::error::ApiError;
+
+pub const SUCCESS: u8 = 0;
+pub const FAILURE: u8 = 1;
+pub const USAGE: u8 = 2;
+pub const WRONG_OUTPUT: u8 = 3;
+pub const NOT_SET_UP: u8 = 4;
+pub const VIM_NOT_FOUND: u8 = 5;
+pub const VIM_FAILED: u8 = 6;
+pub const UPLOAD_FAILED: u8 = 7;
+pub const NETWORK: u8 = 10;
+pub const TIMEOUT: u8 = 11;
+pub const AUTH: u8 = 12;
+pub const NOT_FOUND: u8 = 13;
+pub const SERVER: u8 = 14;
+pub const MALFORMED_RESPONSE: u8 = 15;
+pub const VERSION_MISMATCH: u8 = 16;
+
+pub const TABLE: &[(u8, &str)] = &[
+    (SUCCESS,            "Успех"),
+    (FAILURE,            "Друга грешка"),
+    (USAGE,              "Грешни аргументи"),
+    (WRONG_OUTPUT,       "Резултатът не съвпада с очаквания"),
+    (NOT_SET_UP,         "Няма активиран потребител на този компютър"),
+    (VIM_NOT_FOUND,      "Не е намерен Vim"),
+    (VIM_FAILED,         "Vim излезе с грешка"),
+    (UPLOAD_FAILED,      "Решението е вярно, но не беше качено"),
+    (NETWORK,            "Няма връзка със сайта"),
+    (TIMEOUT,            "Сайтът не отговори навреме"),
+    (AUTH,               "Сайтът не прие токена"),
+    (NOT_FOUND,          "Упражнението не е намерено"),
+    (SERVER,             "Сайтът върна грешка"),
+    (MALFORMED_RESPONSE, "Неочакван отговор от сайта"),
+    (VERSION_MISMATCH,   "Версията на клиента вече не се поддържа"),
+];
+
+/// The `TABLE` as text for the help.
+pub fn help() -> String {
+    let mut help = String::from("Изходни кодове:");
+    for (code, description) in TABLE {
+        help.push_str(&format!("\n  {:<3} {}", code, description));
+    }
+
+    help
+}
+
+pub fn for_api_error(error: &ApiError) -> u8 {
+    match error {
+        ApiError::Network(_)            => NETWORK,
+        ApiError::Timeout               => TIMEOUT,
+        ApiError::Auth(_)               => AUTH,
+        ApiError::NotFound(_)           => NOT_FOUND,
+        ApiError::Server { .. }         => SERVER,
+        ApiError::MalformedResponse(_)  => MALFORMED_RESPONSE,
+        ApiError::VersionMismatch(_)    => VERSION_MISMATCH,
+    }
+}
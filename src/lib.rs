@@ -1,2 +1,14 @@
+// Newer versions of clippy prefer `unwrap_or_default()` and `cloned()`, but the older code doesn't
+// need rewriting just for that
+#![allow(clippy::unwrap_or_default, clippy::map_clone)]
+
 pub mod vim;
+pub mod accounts;
+pub mod config;
 pub mod controller;
+pub mod doctor;
+pub mod error;
+pub mod exit_code;
+pub mod report;
+pub mod update;
+pub mod version;
@@ -1,21 +1,84 @@
-use std::process::{self, ExitCode};
-use std::time::Instant;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use is_terminal::IsTerminal;
+use serde::Serialize;
 use url::Url;
-use similar::{TextDiff, ChangeTag};
-
-use vim_fmi::controller::{Controller, read_user};
-use vim_fmi::vim::{Vim, Keylog};
+use similar::{Change, TextDiff};
+use tracing::debug;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
+
+use vim_fmi::config::{ColorChoice, Config, DiffStyle, Profile, VimInterface, DEFAULT_PROFILE};
+use vim_fmi::accounts::{Accounts, read_passphrase};
+use vim_fmi::controller::{Controller, UploadResponse, User, VimMeta};
+use vim_fmi::doctor;
+use vim_fmi::error::{ApiError, UploadFailed};
+use vim_fmi::exit_code;
+use vim_fmi::report::{self, Keys, TaskReport, Timing};
+use vim_fmi::update::Updater;
+use vim_fmi::version::{VersionStatus, CLIENT_VERSION};
+use vim_fmi::vim::{Vim, VimError};
 
 #[derive(Debug, Parser)]
 #[command(name = "vim-fmi")]
 #[command(about = "Клиент за курса по Vim във ФМИ", long_about = None)]
+#[command(after_long_help = exit_code::help())]
 struct Cli {
+    /// Адрес на сайта, с който да работи клиента (вместо този от профила). Акаунтите за друг сайт
+    /// се пазят отделно
+    #[arg(long, global = true, env = "VIMFMI_HOST")]
+    host: Option<Url>,
+
+    /// Профил от конфигурационния файл, с отделен сайт и потребител
+    #[arg(long, global = true, env = "VIMFMI_PROFILE")]
+    profile: Option<String>,
+
+    /// Акаунт, с който да се работи (вместо текущо избрания)
+    #[arg(long, global = true, env = "VIMFMI_USER")]
+    user: Option<String>,
+
+    /// Формат на изхода: за хора или JSON за скриптове и плъгини
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+
+    /// Показва заявките към сайта, командата за Vim и временните файлове
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Като --verbose, но показва и цялото vimrc, с което се пуска Vim
+    #[arg(long, global = true)]
+    debug: bool,
+
+    /// Използва Vim в терминала, дори да има gvim или mvim (вместо `vim.interface` и `vim.executable`)
+    #[arg(long, global = true, conflicts_with = "gui")]
+    terminal: bool,
+
+    /// Използва Vim с графичен интерфейс, като gvim или mvim (вместо `vim.interface` и `vim.executable`)
+    #[arg(long, global = true)]
+    gui: bool,
+
+    /// Допълнителен аргумент за Vim (добавя се към `vim.args`), по един на флаг, например
+    /// `--vim-arg -T --vim-arg xterm`. Позволени са само такива, които променят изгледа
+    #[arg(long, global = true, value_name = "ARG", allow_hyphen_values = true)]
+    vim_arg: Vec<String>,
+
+    /// Променлива на средата за Vim, като `TERM=xterm-256color` (добавя се към `vim.env`)
+    #[arg(long, global = true, value_name = "NAME=VALUE", value_parser = parse_env_var)]
+    vim_env: Vec<(String, String)>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Human,
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Инициализира потребителя, който ще праща решения
@@ -23,6 +86,9 @@ enum Commands {
     Setup {
         /// Токен, генериран в сайта (https://vim-fmi.bg/user_tokens)
         user_token: String,
+        /// Криптира токена с парола, която ще се пита при всяко използване
+        #[arg(long)]
+        encrypt: bool,
     },
 
     /// Стартира упражнение с подадения идентификатор
@@ -30,9 +96,8 @@ enum Commands {
     Put {
         /// Идентификатора на дадено упражнение
         task_id: String,
-        /// Ако е подадено, няма да се изтегли личното vimrc от сайта
-        #[arg(long)]
-        novimrc: bool,
+        #[command(flatten)]
+        session: SessionArgs,
     },
 
     /// Стартира свободно упражнение с подадения идентификатор
@@ -40,195 +105,750 @@ enum Commands {
     Free {
         /// Идентификатора на дадено свободно упражнение
         free_task_id: String,
-        /// Ако е подадено, няма да се изтегли личното vimrc от сайта
-        #[arg(long)]
-        novimrc: bool,
+        #[command(flatten)]
+        session: SessionArgs,
     },
 
     /// Стартира Vim-а, който програмата може да намери. За тестване
     Vim {
-        /// Ако е подадено, няма да се изтегли личното vimrc от сайта
-        #[arg(long)]
-        novimrc: bool,
+        #[command(flatten)]
+        session: SessionArgs,
     },
 
+    /// Показва кой акаунт и сайт се използват в момента
+    Whoami,
+
+    /// Изтрива токена на текущия акаунт от този компютър
+    Logout {
+        /// Акаунт, чийто токен да бъде изтрит (вместо текущия)
+        name: Option<String>,
+    },
+
+    /// Проверява в сайта дали токенът на текущия акаунт все още е валиден
+    CheckToken,
+
+    /// Управлява акаунтите, активирани на този компютър
+    #[command(subcommand)]
+    Accounts(AccountsCommands),
+
+    /// Показва и променя настройките в конфигурационния файл
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
     /// Показва текущата версия на клиента
     Version,
+
+    /// Проверява дали всичко нужно за решаване на упражнения работи на този компютър
+    Doctor,
+
+    /// Проверява дали специалните клавиши (стрелки, F1-F12, Home/End...) се записват правилно от
+    /// Vim-а на този компютър
+    CheckKeys,
+
+    /// Изтегля и инсталира последната версия на клиента
+    SelfUpdate {
+        /// Само проверява дали има нова версия, без да я инсталира
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+/// Options for commands that start a Vim session
+#[derive(Debug, Args)]
+struct SessionArgs {
+    /// Ако е подадено, няма да се изтегли личното vimrc от сайта
+    #[arg(long)]
+    novimrc: bool,
+
+    /// Запазва vimrc-то, входа и записа на клавишите след края на сесията, по желание в DIR
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true)]
+    keep: Option<Option<PathBuf>>,
+}
+
+#[derive(Debug, Subcommand)]
+enum AccountsCommands {
+    /// Показва всички акаунти, текущият е отбелязан със `*`
+    List,
+
+    /// Избира акаунта, с който да се пращат решения
+    #[command(arg_required_else_help = true)]
+    Switch {
+        name: String,
+    },
+
+    /// Изтрива акаунт и токена му от този компютър
+    #[command(arg_required_else_help = true)]
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Показва стойността на дадена настройка, например `vim.executable`
+    #[command(arg_required_else_help = true)]
+    Get {
+        key: String,
+    },
+
+    /// Записва стойност на дадена настройка, например `vim-fmi config set diff unified`
+    #[command(arg_required_else_help = true)]
+    Set {
+        key: String,
+        value: String,
+    },
+
+    /// Показва всички настройки и пътя до конфигурационния файл
+    List,
 }
 
 fn main() -> ExitCode {
     let args = Cli::parse();
+    init_logging(&args);
 
-    if let Err(e) = run(&args) {
-        eprintln!("Грешка: {}", e);
-        return ExitCode::FAILURE;
+    match run(&args) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => ExitCode::from(report_error(&e, args.format)),
     }
-
-    ExitCode::SUCCESS
 }
 
-fn run(args: &Cli) -> anyhow::Result<()> {
-    let host =
-        if cfg!(debug_assertions) {
-            Url::parse("http://localhost:3000")?
+// Logs go to stderr, so they don't get mixed up with `--format json` output
+fn init_logging(args: &Cli) {
+    let level =
+        if args.debug {
+            LevelFilter::TRACE
+        } else if args.verbose {
+            LevelFilter::DEBUG
         } else {
-            Url::parse("https://vim-fmi.bg")?
+            LevelFilter::WARN
         };
 
+    // Only this crate's events, hyper's connection details are just noise here
+    let targets = Targets::new().with_target("vim_fmi", level);
+
+    tracing_subscriber::fmt().
+        with_max_level(level).
+        with_writer(std::io::stderr).
+        with_ansi(std::io::stderr().is_terminal()).
+        finish().
+        with(targets).
+        init();
+}
+
+fn report_error(error: &anyhow::Error, format: OutputFormat) -> u8 {
+    let api_error = error.downcast_ref::<ApiError>();
+    let hint = api_error.and_then(ApiError::hint);
+    let code = error_exit_code(error);
+
+    if format == OutputFormat::Json {
+        print_json(&serde_json::json!({
+            "error": format!("{:#}", error),
+            "hint": hint,
+            "exit_code": code,
+        }));
+        return code;
+    }
+
+    eprintln!("Грешка: {:#}", error);
+    if let Some(hint) = hint {
+        eprintln!("{}", hint);
+    }
+
+    code
+}
+
+fn error_exit_code(error: &anyhow::Error) -> u8 {
+    let api_error = error.downcast_ref::<ApiError>();
+
+    // A network problem is a network problem, even while uploading
+    if let Some(api_error @ (ApiError::Network(_) | ApiError::Timeout)) = api_error {
+        return exit_code::for_api_error(api_error);
+    }
+    if error.downcast_ref::<UploadFailed>().is_some() {
+        return exit_code::UPLOAD_FAILED;
+    }
+    if let Some(api_error) = api_error {
+        return exit_code::for_api_error(api_error);
+    }
+
+    match error.downcast_ref::<VimError>() {
+        Some(VimError::NotFound(_) | VimError::NoVersion(..)) => exit_code::VIM_NOT_FOUND,
+        Some(VimError::Failed(_)) => exit_code::VIM_FAILED,
+        None => exit_code::FAILURE,
+    }
+}
+
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    value.split_once('=').
+        map(|(name, value)| (name.to_owned(), value.to_owned())).
+        ok_or_else(|| format!("очаквам NAME=VALUE, а не `{}`", value))
+}
+
+fn run(args: &Cli) -> anyhow::Result<u8> {
+    // These don't need the config, and `config set` is how a broken one gets fixed
     match &args.command {
-        Commands::Vim { novimrc } => {
-            let mut controller = Controller::new(host)?;
-            let input_path = controller.create_file("scratch", "")?;
-            let log_path = controller.create_file("log", "")?;
-
-            if !novimrc {
-                if let Ok(Some(user)) = read_user() {
-                    if let Err(e) = controller.download_vimrc(&user.token) {
-                        eprintln!("Имаше проблем с изтеглянето на твоето vimrc, използваме стандартното: {e}");
-                    }
-                }
+        Commands::Config(ConfigCommands::Set { key, value }) => {
+            Config::set(key, value)?;
+            return Ok(exit_code::SUCCESS);
+        },
+        Commands::Version => {
+            if args.format == OutputFormat::Json {
+                print_json(&serde_json::json!({ "version": ::clap::crate_version!() }));
+            } else {
+                println!(::clap::crate_version!());
             }
+            return Ok(exit_code::SUCCESS);
+        },
+        _ => {},
+    }
 
-            let vimrc_path = controller.vimrc_path();
-            let vim = Vim::new(vimrc_path)?;
+    let mut config = Config::load()?;
+    if let Ok(executable) = std::env::var("VIMFMI_EXECUTABLE") {
+        config.vim.executable = Some(executable);
+    }
+    // The flags are about this one run, so they win over an executable that's always used
+    if args.terminal || args.gui {
+        config.vim.executable = None;
+    }
+    if args.terminal {
+        config.vim.interface = VimInterface::Terminal;
+    } else if args.gui {
+        config.vim.interface = VimInterface::Gui;
+    }
+    config.vim.args.extend(args.vim_arg.iter().cloned());
+    config.vim.env.extend(args.vim_env.iter().cloned());
 
-            let (_, log_bytes) = vim.run(&input_path, &log_path)?;
+    let mut profile = config.profile(args.profile.as_deref())?;
 
-            let keylog = Keylog::new(&log_bytes);
-            let script: String = keylog.into_iter().collect();
-            println!("Клавишите ти бяха:\n{}", script);
+    if let Some(host) = &args.host {
+        profile.override_host(host);
+    }
+
+    debug!(profile = %profile.name, host = %profile.host, data_dir = %profile.data_dir.display(), "using profile");
+
+    let accounts = Accounts::new(&profile.data_dir)?;
+    let session = Session { args, config, profile, accounts };
+
+    match &args.command {
+        Commands::Vim { session: session_args } => run_vim(&session, session_args),
+        Commands::Setup { user_token, encrypt } => run_setup(&session, user_token, *encrypt),
+        Commands::Put { task_id, session: session_args } => {
+            run_task(&session, TaskKind::Task, task_id, session_args)
+        },
+        Commands::Free { free_task_id, session: session_args } => {
+            run_task(&session, TaskKind::FreeTask, free_task_id, session_args)
         },
-        Commands::Setup { user_token } => {
-            let controller = Controller::new(host)?;
-            let _ = controller.setup_user(user_token)?;
+        Commands::Whoami => {
+            let Some(user) = session.read_user()? else {
+                return Ok(session.not_set_up());
+            };
+            // Unwrap: there's a user, so there's a name for it
+            let name = args.user.clone().or(session.accounts.current_name()?).unwrap();
 
-            println!("Токена ти е активиран, вече можеш да пускаш решения");
+            println!("Акаунт:       {}", name);
+            println!("Факултетен №: {}", user.faculty_number);
+            println!("Профил:       {}", session.profile.name);
+            println!("Сайт:         {}", session.profile.host);
+
+            Ok(exit_code::SUCCESS)
         },
+        Commands::Logout { name } => {
+            let name = name.clone().or(args.user.clone()).or(session.accounts.current_name()?);
+            let Some(name) = name else {
+                let names = session.accounts.list()?;
+                if names.is_empty() {
+                    println!("Няма активиран акаунт на този компютър");
+                    return Ok(exit_code::NOT_SET_UP);
+                }
 
-        // TODO: Extract some common code to set up Vim, communicate result
-        Commands::Put { task_id, novimrc } => {
-            let Some(user) = read_user()? else {
-                eprintln!("Не си се активирал на този компютър.");
-                eprintln!("Иди в сайта (https://vim-fmi.bg/user_tokens), създай си token и извикай:");
-                eprintln!();
-                eprintln!("  vim-fmi setup <token>");
-                eprintln!();
-                process::exit(1);
+                println!("Има няколко акаунта и никой не е текущ: {}", names.join(", "));
+                println!("Избери чий токен да бъде изтрит с `vim-fmi logout <име>`");
+                return Ok(exit_code::USAGE);
             };
 
-            let mut controller = Controller::new(host)?;
-            let task = controller.download_task(task_id)?;
+            session.accounts.remove(&name)?;
+            println!("Токенът на `{}` е изтрит от този компютър", name);
 
-            if !novimrc {
-                if let Err(e) = controller.download_vimrc(&user.token) {
-                    eprintln!("Имаше проблем с изтеглянето на твоето vimrc, използваме стандартното: {e}");
-                }
+            Ok(exit_code::SUCCESS)
+        },
+        Commands::CheckToken => {
+            let Some(user) = session.read_user()? else {
+                return Ok(session.not_set_up());
+            };
+
+            let mut controller = Controller::new(&session.profile, &session.config)?;
+            controller.authenticate(&user.token)?;
+            let server_user = controller.validate_user()?;
+
+            println!("Токенът е валиден, факултетен номер: {}", server_user.faculty_number);
+
+            Ok(exit_code::SUCCESS)
+        },
+        Commands::Accounts(AccountsCommands::List) => {
+            let current = session.accounts.current_name()?;
+
+            for name in session.accounts.list()? {
+                let marker = if current.as_ref() == Some(&name) { "*" } else { " " };
+                println!("{} {}", marker, name);
             }
 
-            let input_filename = format!("input.{}", task.file_extension.unwrap_or(String::from("txt")));
-            let input_path = controller.create_file(&input_filename, &task.input)?;
-            let log_path = controller.create_file("log", "")?;
-            let vimrc_path = controller.vimrc_path();
-            let vim = Vim::new(vimrc_path)?;
-
-            let start_time = Instant::now();
-            let (output, log_bytes) = vim.run(&input_path, &log_path)?;
-            let elapsed_time = start_time.elapsed().as_millis();
-
-            let keylog = Keylog::new(&log_bytes);
-            let script: String = keylog.into_iter().collect();
-
-            let trimmed_output = output.trim();
-            let trimmed_output_lines = normalized_lines(trimmed_output);
-            let task_output_lines = normalized_lines(&task.output);
-
-            if trimmed_output_lines == task_output_lines {
-                if controller.upload(task_id, log_bytes, &vim.executable, elapsed_time)? {
-                    println!("Супер, решението е качено. Клавишите ти бяха:\n{}", script);
-                } else {
-                    println!("Имаше проблем при качване на решението, пробвай пак.");
-                    println!("Ако не проработи 2-3 пъти, пиши в Discord или по мейл.");
-                }
-            } else {
-                println!("Не се получи, клавишите ти бяха:\n{}", script);
-                println!();
-                println!("Ето ти разликата между твоя опит и очаквания:");
-                println!();
-                print_diff(&task.output, trimmed_output);
+            Ok(exit_code::SUCCESS)
+        },
+        Commands::Accounts(AccountsCommands::Switch { name }) => {
+            session.accounts.switch(name)?;
+            println!("Текущият акаунт е `{}`", name);
+
+            Ok(exit_code::SUCCESS)
+        },
+        Commands::Accounts(AccountsCommands::Remove { name }) => {
+            session.accounts.remove(name)?;
+            println!("Акаунтът `{}` е изтрит от този компютър", name);
+
+            Ok(exit_code::SUCCESS)
+        },
+        Commands::Config(ConfigCommands::Get { key }) => {
+            match session.config.get(key)? {
+                Some(value) => println!("{}", value),
+                None => return Err(anyhow::anyhow!("Настройката `{}` не е зададена", key)),
             }
+
+            Ok(exit_code::SUCCESS)
         },
-        Commands::Free { free_task_id, novimrc } => {
-            let Some(user) = read_user()? else {
-                eprintln!("Не си се активирал на този компютър.");
-                eprintln!("Иди в сайта (https://vim-fmi.bg/user_tokens), създай си token и извикай:");
-                eprintln!();
-                eprintln!("  vim-fmi setup <token>");
-                eprintln!();
-                process::exit(1);
-            };
+        Commands::Config(ConfigCommands::List) => {
+            println!("# {}", Config::path()?.display());
 
-            let mut controller = Controller::new(host)?;
-            let task = controller.download_free_task(free_task_id)?;
+            for (key, value) in session.config.list()? {
+                println!("{} = {}", key, value);
+            }
 
-            if !novimrc {
-                if let Err(e) = controller.download_vimrc(&user.token) {
-                    eprintln!("Имаше проблем с изтеглянето на твоето vimrc, използваме стандартното: {e}");
-                }
+            Ok(exit_code::SUCCESS)
+        },
+        // Handled before the config is loaded
+        Commands::Config(ConfigCommands::Set { .. }) | Commands::Version => unreachable!(),
+        Commands::Doctor => run_doctor(&session),
+        Commands::CheckKeys => run_check_keys(&session),
+        Commands::SelfUpdate { check } => run_self_update(&session, *check),
+    }
+}
+
+// Everything commands need, after the configuration has been resolved
+struct Session<'a> {
+    args: &'a Cli,
+    config: Config,
+    profile: Profile,
+    accounts: Accounts,
+}
+
+impl<'a> Session<'a> {
+    fn read_user(&self) -> anyhow::Result<Option<User>> {
+        self.accounts.read(self.args.user.as_deref())
+    }
+
+    fn novimrc(&self, session_args: &SessionArgs) -> bool {
+        session_args.novimrc || self.config.novimrc
+    }
+
+    fn keep_session(&self, controller: &mut Controller, session_args: &SessionArgs) -> anyhow::Result<()> {
+        if let Some(target) = &session_args.keep {
+            let path = controller.keep_session(target.as_deref())?;
+            eprintln!("Файловете от сесията ще останат в {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    fn json(&self) -> bool {
+        self.args.format == OutputFormat::Json
+    }
+
+    fn not_set_up(&self) -> u8 {
+        if self.json() {
+            print_json(&serde_json::json!({
+                "error": "Не си се активирал на този компютър",
+                "exit_code": exit_code::NOT_SET_UP,
+            }));
+        } else {
+            print_setup_instructions(&self.profile, self.args.user.as_deref());
+        }
+
+        exit_code::NOT_SET_UP
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TaskKind {
+    Task,
+    FreeTask,
+}
+
+fn run_vim(session: &Session, session_args: &SessionArgs) -> anyhow::Result<u8> {
+    let mut controller = Controller::new(&session.profile, &session.config)?;
+    session.keep_session(&mut controller, session_args)?;
+    let input_path = controller.create_file("scratch", "")?;
+    let log_path = controller.create_file("log", "")?;
+
+    if !session.novimrc(session_args) {
+        if let Ok(Some(user)) = session.read_user() {
+            controller.authenticate(&user.token)?;
+
+            if let Err(e) = controller.download_vimrc() {
+                eprintln!("Имаше проблем с изтеглянето на твоето vimrc, използваме стандартното: {e}");
             }
+        }
+    }
 
-            let input_filename = format!("input.{}", task.file_extension.unwrap_or(String::from("txt")));
-            let input_path = controller.create_file(&input_filename, &task.input)?;
-            let log_path = controller.create_file("log", "")?;
-            let vimrc_path = controller.vimrc_path();
-            let vim = Vim::new(vimrc_path)?;
-
-            let start_time = Instant::now();
-            let (output, log_bytes) = vim.run(&input_path, &log_path)?;
-            let elapsed_time = start_time.elapsed().as_millis();
-
-            let keylog = Keylog::new(&log_bytes);
-            let script: String = keylog.into_iter().collect();
-
-            let trimmed_output = output.trim();
-            let trimmed_output_lines = normalized_lines(trimmed_output);
-            let task_output_lines = normalized_lines(&task.output);
-
-            if trimmed_output_lines == task_output_lines {
-                if controller.upload_free_task(free_task_id, log_bytes, &vim.executable, elapsed_time)? {
-                    println!("Супер, решението е качено. Клавишите ти бяха:\n{}", script);
-                } else {
-                    println!("Имаше проблем при качване на решението, пробвай пак.");
-                    println!("Ако не проработи 2-3 пъти, пиши в Discord или по мейл.");
-                }
-            } else {
-                println!("Не се получи, клавишите ти бяха:\n{}", script);
-                println!();
-                println!("Ето ти разликата между твоя опит и очаквания:");
-                println!();
-                print_diff(&task.output, trimmed_output);
+    let vimrc_path = controller.vimrc_path();
+    let vim = Vim::new(vimrc_path, controller.home_path()?, &session.config.vim)?;
+
+    let (_, log_bytes, _) = vim.run(&input_path, &log_path, None)?;
+    let keys = Keys::from_log(&log_bytes);
+
+    if session.json() {
+        print_json(&keys);
+    } else {
+        println!("Клавишите ти бяха:\n{}", keys.script);
+    }
+
+    Ok(exit_code::SUCCESS)
+}
+
+fn run_setup(session: &Session, user_token: &str, encrypt: bool) -> anyhow::Result<u8> {
+    // Before the token is used up on the server, in case the confirmation doesn't match
+    let passphrase = if encrypt { Some(read_passphrase(true)?) } else { None };
+
+    let mut controller = Controller::new(&session.profile, &session.config)?;
+    let user = controller.setup_user(user_token)?;
+    let name = session.args.user.clone().unwrap_or_else(|| user.faculty_number.clone());
+
+    session.accounts.write(&name, &user, passphrase.as_deref())?;
+    session.accounts.switch(&name)?;
+
+    if session.json() {
+        print_json(&serde_json::json!({
+            "account": name,
+            "faculty_number": user.faculty_number,
+            "encrypted": encrypt,
+        }));
+    } else {
+        println!("Токена ти е активиран като акаунт `{}`, вече можеш да пускаш решения", name);
+    }
+
+    Ok(exit_code::SUCCESS)
+}
+
+fn run_doctor(session: &Session) -> anyhow::Result<u8> {
+    let checks = doctor::run_checks(
+        &session.profile,
+        &session.config,
+        &session.accounts,
+        session.args.user.as_deref(),
+    )?;
+    let failed = checks.iter().any(|check| check.status == doctor::Status::Error);
+
+    if session.json() {
+        print_json(&serde_json::json!({ "checks": checks, "ok": !failed }));
+    } else {
+        for check in &checks {
+            let marker = match check.status {
+                doctor::Status::Ok => "[OK]",
+                doctor::Status::Warning => "[??]",
+                doctor::Status::Error => "[!!]",
+            };
+
+            println!("{} {}: {}", marker, check.name, check.details);
+            if let Some(fix) = &check.fix {
+                println!("     -> {}", fix);
             }
+        }
+    }
+
+    Ok(if failed { exit_code::FAILURE } else { exit_code::SUCCESS })
+}
+
+fn run_check_keys(session: &Session) -> anyhow::Result<u8> {
+    let controller = Controller::new(&session.profile, &session.config)?;
+    let vim = Vim::new(controller.vimrc_path(), controller.home_path()?, &session.config.vim)?;
+    let checks = doctor::check_special_keys(&vim, &controller)?;
+    let failed: Vec<_> = checks.iter().filter(|check| !check.ok).collect();
+
+    if session.json() {
+        print_json(&serde_json::json!({ "keys": checks, "ok": failed.is_empty() }));
+    } else if failed.is_empty() {
+        println!("Всички {} специални клавиша се записват правилно от {}", checks.len(), vim.executable);
+    } else {
+        println!("Някои клавиши не се разчитат правилно от записа на {}:", vim.executable);
+        for check in &failed {
+            println!("  {} -> {:?}", check.expected, check.recorded);
+        }
+        println!();
+        println!("Не ползвай тези клавиши в решенията си и пиши в Discord с изхода на `vim-fmi doctor`.");
+    }
+
+    Ok(if failed.is_empty() { exit_code::SUCCESS } else { exit_code::FAILURE })
+}
+
+fn run_self_update(session: &Session, check: bool) -> anyhow::Result<u8> {
+    let updater = Updater::new(&session.config)?;
+    let release = updater.latest_release()?;
+    let latest = release.version()?;
+    let current = semver::Version::parse(CLIENT_VERSION)?;
+    let outdated = current < latest;
+
+    if outdated && !check {
+        updater.install(&release)?;
+    }
+
+    if session.json() {
+        print_json(&serde_json::json!({
+            "current": current.to_string(),
+            "latest": latest.to_string(),
+            "updated": outdated && !check,
+        }));
+    } else if !outdated {
+        println!("Имаш последната версия на клиента ({})", current);
+    } else if check {
+        println!("Има нова версия на клиента: {} (твоята е {})", latest, current);
+    } else {
+        println!("Клиентът е обновен от {} до {}", current, latest);
+    }
+
+    Ok(exit_code::SUCCESS)
+}
+
+fn run_task(
+    session: &Session,
+    kind: TaskKind,
+    task_id: &str,
+    session_args: &SessionArgs,
+) -> anyhow::Result<u8> {
+    let Some(user) = session.read_user()? else {
+        return Ok(session.not_set_up());
+    };
+
+    let mut controller = Controller::new(&session.profile, &session.config)?;
+    controller.authenticate(&user.token)?;
+    session.keep_session(&mut controller, session_args)?;
+
+    // Better to find out now than after solving the task. If the server can't say, the upload will
+    // find out anyway.
+    match controller.client_versions().map(|versions| versions.status()) {
+        Ok(VersionStatus::Unsupported { reason }) => {
+            return Err(ApiError::VersionMismatch(reason).into());
         },
-        Commands::Version => {
-            println!(::clap::crate_version!());
+        Ok(VersionStatus::Outdated { latest }) => {
+            eprintln!("Има нова версия на клиента ({}), обнови го с `vim-fmi self-update`.", latest);
         },
+        Ok(VersionStatus::UpToDate) => (),
+        Err(e) => eprintln!("Не можа да се провери версията на клиента: {:#}", e),
+    }
+
+    let task = match kind {
+        TaskKind::Task => controller.download_task(task_id)?,
+        TaskKind::FreeTask => controller.download_free_task(task_id)?,
+    };
+
+    if !session.novimrc(session_args) {
+        if let Err(e) = controller.download_vimrc() {
+            eprintln!("Имаше проблем с изтеглянето на твоето vimrc, използваме стандартното: {e}");
+        }
     }
 
-    Ok(())
+    controller.enable_match_indicator(&task.output)?;
+
+    let time_limit = report::time_limit(task.time_limit, session.config.vim.time_limit);
+    controller.enable_session_timer(time_limit)?;
+    if let Some(seconds) = time_limit {
+        eprintln!("Имаш {} секунди, след това файлът ще бъде записан и Vim ще се затвори.", seconds);
+    }
+
+    let input_filename = format!("input.{}", task.file_extension.unwrap_or(String::from("txt")));
+    let input_path = controller.create_file(&input_filename, &task.input)?;
+    let log_path = controller.create_file("log", "")?;
+    let vimrc_path = controller.vimrc_path();
+    let vim = Vim::new(vimrc_path, controller.home_path()?, &session.config.vim)?;
+
+    let start_time = Instant::now();
+    let (output, log_bytes, killed) = vim.run(&input_path, &log_path, time_limit.map(Duration::from_secs))?;
+    let elapsed_time = start_time.elapsed();
+
+    let timing = Timing {
+        elapsed_time: elapsed_time.as_millis(),
+        idle_time: controller.idle_time(),
+        timed_out: killed || controller.timed_out(),
+    };
+    if killed {
+        eprintln!("Vim не се затвори навреме и беше спрян, използваме това, което е записано във файла.");
+    }
+    if timing.timed_out && !session.json() {
+        println!("Времето изтече.");
+        println!();
+    }
+
+    let keys = Keys::from_log(&log_bytes);
+    let trimmed_output = output.trim();
+    let passed = report::normalized_lines(trimmed_output) == report::normalized_lines(&task.output);
+
+    let mut task_report = TaskReport {
+        task_id: task_id.to_owned(),
+        passed,
+        keys,
+        timing,
+        diff: Vec::new(),
+        uploaded: false,
+        message: None,
+        result: None,
+    };
+
+    if !passed {
+        task_report.diff = report::diff_hunks(&task.output, trimmed_output);
+
+        if session.json() {
+            print_json(&task_report);
+        } else {
+            println!("Не се получи, клавишите ти бяха:\n{}", task_report.keys.script);
+            println!();
+            println!("Ето ти разликата между твоя опит и очаквания:");
+            println!();
+            print_diff(&task.output, trimmed_output, &session.config);
+        }
+
+        return Ok(exit_code::WRONG_OUTPUT);
+    }
+
+    let vim_meta = VimMeta { executable: &vim.executable, info: vim.info.as_ref() };
+    let upload_response = match kind {
+        TaskKind::Task => {
+            controller.upload(task_id, log_bytes, &vim_meta, &timing)
+        },
+        TaskKind::FreeTask => {
+            controller.upload_free_task(task_id, log_bytes, &vim_meta, &timing)
+        },
+    };
+
+    match upload_response {
+        Ok(response) => {
+            task_report.uploaded = true;
+            task_report.message = response.message.clone();
+            task_report.result = Some(response);
+        },
+        Err(e) if session.json() => {
+            // The report is more useful to a script than just the error
+            task_report.message = Some(format!("{:#}", e));
+            print_json(&task_report);
+            return Ok(error_exit_code(&e.context(UploadFailed)));
+        },
+        Err(e) => return Err(e.context(UploadFailed)),
+    }
+
+    if session.json() {
+        print_json(&task_report);
+    } else {
+        println!("Супер, решението е качено. Клавишите ти бяха:\n{}", task_report.keys.script);
+
+        if let Some(result) = &task_report.result {
+            print_upload_result(result);
+        }
+    }
+
+    Ok(exit_code::SUCCESS)
 }
 
-fn normalized_lines(input: &str) -> Vec<String> {
-    input.trim().lines().map(|s| s.trim_end().to_string()).collect()
+fn print_upload_result(result: &UploadResponse) {
+    if let Some(message) = &result.message {
+        println!();
+        println!("{}", message);
+    }
+
+    if let Some(score) = result.score {
+        println!();
+        println!("Резултат: {} клавиша", score);
+    }
+    match (result.best_score, result.rank) {
+        (Some(best_score), Some(rank)) => println!("Най-добър резултат: {} (място в класирането: {})", best_score, rank),
+        (Some(best_score), None) => println!("Най-добър резултат: {}", best_score),
+        (None, Some(rank)) => println!("Място в класирането: {}", rank),
+        (None, None) => (),
+    }
+    if result.improved == Some(true) {
+        println!("Подобри най-добрия си резултат!");
+    }
 }
 
-fn print_diff(input: &str, output: &str) {
+fn print_json<T: Serialize>(value: &T) {
+    // Unwrap: these are all plain data structures that can always be serialized
+    println!("{}", serde_json::to_string(value).unwrap());
+}
+
+fn print_setup_instructions(profile: &Profile, user: Option<&str>) {
+    let mut extra_args = String::new();
+    if profile.name != DEFAULT_PROFILE {
+        extra_args.push_str(&format!(" --profile {}", profile.name));
+    }
+    if let Some(user) = user {
+        extra_args.push_str(&format!(" --user {}", user));
+    }
+
+    let tokens_url = profile.host.join("/user_tokens").
+        map(String::from).
+        unwrap_or_else(|_| profile.host.to_string());
+
+    eprintln!("Не си се активирал на този компютър.");
+    eprintln!("Иди в сайта ({}), създай си token и извикай:", tokens_url);
+    eprintln!();
+    eprintln!("  vim-fmi{} setup <token>", extra_args);
+    eprintln!();
+}
+
+fn print_diff(input: &str, output: &str, config: &Config) {
     let diff = TextDiff::from_lines(input, output);
+    let color = match config.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+    };
+
+    match config.diff {
+        DiffStyle::Full => {
+            for change in diff.iter_all_changes() {
+                print_change(&change, color);
+            }
+        },
+        DiffStyle::Unified => {
+            for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+                println!("{}", hunk.header());
 
-    for change in diff.iter_all_changes() {
-        let sign = match change.tag() {
-            ChangeTag::Delete => "-",
-            ChangeTag::Insert => "+",
-            ChangeTag::Equal => " ",
-        };
+                for change in hunk.iter_changes() {
+                    print_change(&change, color);
+                }
+            }
+        },
+    }
+}
+
+fn print_change(change: &Change<&str>, color: bool) {
+    let sign = report::diff_sign(change.tag());
+    let ansi_color = match sign {
+        "-" => "\x1b[31m",
+        "+" => "\x1b[32m",
+        _ => "",
+    };
+
+    if color && !ansi_color.is_empty() {
+        print!("{}{}{}\x1b[0m", ansi_color, sign, change);
+    } else {
         print!("{}{}", sign, change);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vim_arg_example_from_the_help() {
+        let args = Cli::try_parse_from(["vim-fmi", "--vim-arg", "-T", "--vim-arg", "xterm", "vim"]).unwrap();
+
+        assert_eq!(args.vim_arg, ["-T", "xterm"]);
+        vim_fmi::vim::validate_args(&args.vim_arg).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+" Shows "[OK]" in the statusline when the buffer matches the expected output of
+" the task, so there's no need to guess when it's time to `:wq`.
+"
+" The comparison mirrors the one the client makes after Vim exits: leading and
+" trailing whitespace of the whole text and trailing whitespace of each line
+" are ignored. That includes the carriage returns `readfile()` keeps when the
+" expected output has CRLF line endings.
+
+let s:expected_path = expand('<sfile>:p:h') . '/expected'
+
+function! s:NormalizedLines(lines) abort
+  let text = join(a:lines, "\n")
+  let text = substitute(text, '^\(\_s\|\r\)\+', '', '')
+  let text = substitute(text, '\(\_s\|\r\)\+$', '', '')
+
+  if text == ''
+    return []
+  endif
+
+  return map(split(text, "\n", 1), 'substitute(v:val, ''[[:space:]\r]\+$'', "", "")')
+endfunction
+
+let s:expected_lines = s:NormalizedLines(readfile(s:expected_path))
+let s:matches = 0
+
+function! s:Update() abort
+  let s:matches = (s:NormalizedLines(getline(1, '$')) ==# s:expected_lines)
+endfunction
+
+function! VimFmiMatchIndicator() abort
+  return s:matches ? '[OK] ' : ''
+endfunction
+
+augroup VimFmiMatchIndicator
+  autocmd!
+  autocmd BufEnter,InsertLeave,CursorHold * call s:Update()
+
+  if exists('##TextChanged')
+    autocmd TextChanged,TextChangedI * call s:Update()
+  endif
+augroup END
+
+let &statusline = '%{VimFmiMatchIndicator()}' . &statusline
@@ -0,0 +1,126 @@
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::controller::UploadResponse;
+use crate::vim::Keylog;
+
+/// The keys from a Vim session, decoded from the `-W` log.
+#[derive(Debug, Serialize)]
+pub struct Keys {
+    pub keys: Vec<String>,
+    pub key_count: usize,
+    pub script: String,
+}
+
+impl Keys {
+    pub fn from_log(log_bytes: &[u8]) -> Self {
+        let keylog = Keylog::new(log_bytes);
+        let keys: Vec<String> = keylog.into_iter().filter(|key| !key.is_empty()).collect();
+        let script = keys.concat();
+
+        Self { key_count: keys.len(), keys, script }
+    }
+}
+
+/// The result of solving a task, with or without a successful upload.
+#[derive(Debug, Serialize)]
+pub struct TaskReport {
+    pub task_id: String,
+    pub passed: bool,
+    #[serde(flatten)]
+    pub keys: Keys,
+    #[serde(flatten)]
+    pub timing: Timing,
+    pub diff: Vec<DiffHunk>,
+    pub uploaded: bool,
+    /// What the server said about the upload, or why it failed
+    pub message: Option<String>,
+    pub result: Option<UploadResponse>,
+}
+
+/// How long a session took, in milliseconds.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Timing {
+    pub elapsed_time: u128,
+    /// The part of it in which nothing happened in Vim, missing if it couldn't be measured
+    pub idle_time: Option<u128>,
+    /// Whether Vim was closed because the time limit ran out
+    pub timed_out: bool,
+}
+
+/// The shorter of the task's limit and the local one, in seconds, since the local limit can only
+/// make a timed task shorter. A limit of 0 is the same as none.
+///
+pub fn time_limit(task_limit: Option<u64>, local_limit: Option<u64>) -> Option<u64> {
+    [task_limit, local_limit].into_iter().flatten().filter(|&seconds| seconds > 0).min()
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffHunk {
+    pub header: String,
+    pub changes: Vec<DiffLine>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    /// One of `-`, `+` or ` `
+    pub sign: &'static str,
+    pub line: String,
+}
+
+/// The lines that are compared to check a solution: trailing whitespace doesn't matter, and
+/// neither do blank lines at the start and end.
+///
+pub fn normalized_lines(input: &str) -> Vec<String> {
+    input.trim().lines().map(|s| s.trim_end().to_string()).collect()
+}
+
+pub fn diff_hunks(expected: &str, actual: &str) -> Vec<DiffHunk> {
+    let diff = TextDiff::from_lines(expected, actual);
+    let mut unified_diff = diff.unified_diff();
+
+    unified_diff.context_radius(3).iter_hunks().map(|hunk| {
+        let changes = hunk.iter_changes().map(|change| {
+            DiffLine {
+                sign: diff_sign(change.tag()),
+                line: change.value().trim_end_matches(['\r', '\n']).to_owned(),
+            }
+        }).collect();
+
+        DiffHunk { header: hunk.header().to_string(), changes }
+    }).collect()
+}
+
+pub fn diff_sign(tag: ChangeTag) -> &'static str {
+    match tag {
+        ChangeTag::Delete => "-",
+        ChangeTag::Insert => "+",
+        ChangeTag::Equal => " ",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_limit_from_either_side() {
+        assert_eq!(time_limit(None, None), None);
+        assert_eq!(time_limit(Some(300), None), Some(300));
+        assert_eq!(time_limit(None, Some(120)), Some(120));
+    }
+
+    #[test]
+    fn time_limit_takes_the_shorter_one() {
+        assert_eq!(time_limit(Some(300), Some(120)), Some(120));
+        assert_eq!(time_limit(Some(60), Some(120)), Some(60));
+    }
+
+    #[test]
+    fn time_limit_of_zero_is_none() {
+        assert_eq!(time_limit(Some(0), None), None);
+        assert_eq!(time_limit(None, Some(0)), None);
+        assert_eq!(time_limit(Some(300), Some(0)), Some(300));
+        assert_eq!(time_limit(Some(0), Some(120)), Some(120));
+    }
+}
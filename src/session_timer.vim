@@ -0,0 +1,54 @@
+" Counts the seconds in which nothing happened in the session and writes them
+" to the `idle` file on exit. With a time limit, warns before the time runs out
+" and then leaves a `timed_out` file, saves the file and quits.
+"
+" A second is idle if the mode, the cursor, the view, the text and the command
+" line are all the same as they were a second ago.
+
+if !exists('*timer_start')
+  finish
+endif
+
+let s:dir = expand('<sfile>:p:h')
+let s:time_limit = str2nr(get(readfile(s:dir . '/time_limit'), 0, '0'))
+let s:warning = s:time_limit > 120 ? 60 : s:time_limit / 2
+let s:warned = 0
+let s:start = localtime()
+let s:idle_seconds = 0
+let s:last_state = []
+
+function! s:State() abort
+  return [mode(1), getpos('.'), winsaveview(), changenr(), getcmdline()]
+endfunction
+
+function! s:Tick(timer) abort
+  let state = s:State()
+  if state ==# s:last_state
+    let s:idle_seconds += 1
+  endif
+  let s:last_state = state
+
+  if s:time_limit <= 0
+    return
+  endif
+
+  let remaining = s:time_limit - (localtime() - s:start)
+
+  if remaining <= 0
+    call writefile(['1'], s:dir . '/timed_out')
+    silent! wall
+    qall!
+  elseif remaining <= s:warning && !s:warned
+    let s:warned = 1
+    echohl WarningMsg
+    echomsg printf('Остават %d секунди до края на сесията', remaining)
+    echohl None
+  endif
+endfunction
+
+augroup VimFmiSessionTimer
+  autocmd!
+  autocmd VimLeavePre * call writefile([string(s:idle_seconds)], s:dir . '/idle')
+augroup END
+
+call timer_start(1000, function('s:Tick'), {'repeat': -1})
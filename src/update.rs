@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::{Cursor, Read};
+
+use anyhow::anyhow;
+use reqwest::blocking::Client;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use url::Url;
+
+use crate::config::Config;
+use crate::controller::build_client;
+use crate::error::ApiError;
+
+/// The target triple this binary was built for, release artifacts are named after it.
+pub const TARGET: &str = env!("VIMFMI_TARGET");
+
+#[cfg(windows)]
+const BINARY_NAME: &str = "vim-fmi.exe";
+#[cfg(not(windows))]
+const BINARY_NAME: &str = "vim-fmi";
+
+/// The parts of a GitHub release that matter for updating.
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: Url,
+}
+
+impl Release {
+    /// Tags look like `v0.2.0`, or `vim-fmi-v0.2.0` when cargo-dist is given the package name.
+    pub fn version(&self) -> ::anyhow::Result<Version> {
+        let version = self.tag_name.trim_start_matches(|c: char| !c.is_ascii_digit());
+
+        Version::parse(version).
+            map_err(|e| anyhow!("Невалидна версия на release-а `{}`: {}", self.tag_name, e))
+    }
+
+    /// The archive built by cargo-dist for the current target: a `.zip` on Windows and a
+    /// `.tar.xz` everywhere else.
+    ///
+    pub fn artifact(&self) -> Option<&Asset> {
+        self.assets.iter().find(|asset| {
+            asset.name.contains(TARGET) &&
+                (asset.name.ends_with(".tar.xz") || asset.name.ends_with(".zip"))
+        })
+    }
+
+    pub fn checksum(&self, artifact: &Asset) -> Option<&Asset> {
+        let name = format!("{}.sha256", artifact.name);
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+pub struct Updater {
+    client: Client,
+    release_url: Url,
+}
+
+impl Updater {
+    pub fn new(config: &Config) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            client: build_client(config.language, &config.http, None)?,
+            release_url: config.update.release_url.clone(),
+        })
+    }
+
+    pub fn latest_release(&self) -> ::anyhow::Result<Release> {
+        let body = self.download(&self.release_url)?;
+        Ok(serde_json::from_slice(&body).map_err(ApiError::from)?)
+    }
+
+    /// Downloads the artifact and its checksum, checks that they match and replaces the running
+    /// executable with the one from the archive.
+    ///
+    pub fn install(&self, release: &Release) -> ::anyhow::Result<()> {
+        let artifact = release.artifact().
+            ok_or_else(|| anyhow!("Няма готова версия на клиента за {}", TARGET))?;
+        let checksum = release.checksum(artifact).
+            ok_or_else(|| anyhow!("Липсва контролна сума за {}", artifact.name))?;
+
+        let archive = self.download(&artifact.browser_download_url)?;
+        let checksum = String::from_utf8(self.download(&checksum.browser_download_url)?)?;
+        verify_checksum(&archive, &checksum)?;
+
+        let binary = extract_binary(&artifact.name, &archive)?;
+
+        let new_executable = tempfile::NamedTempFile::new()?;
+        fs::write(new_executable.path(), binary)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(new_executable.path(), fs::Permissions::from_mode(0o755))?;
+        }
+
+        self_replace::self_replace(new_executable.path()).
+            map_err(|e| anyhow!("Не можа да бъде заменен изпълнимият файл: {}", e))?;
+
+        Ok(())
+    }
+
+    fn download(&self, url: &Url) -> Result<Vec<u8>, ApiError> {
+        debug!(%url, "request");
+        let response = self.client.get(url.clone()).send()?;
+        debug!(%url, status = %response.status(), "response");
+
+        if response.status().is_success() {
+            Ok(response.bytes()?.to_vec())
+        } else {
+            Err(ApiError::from_response(response))
+        }
+    }
+}
+
+/// Checksum files contain the hex-encoded SHA-256 of the artifact, optionally followed by its
+/// name, like the output of `sha256sum`.
+///
+fn verify_checksum(bytes: &[u8], checksum_file: &str) -> ::anyhow::Result<()> {
+    let expected = checksum_file.split_whitespace().next().unwrap_or_default().to_lowercase();
+    let actual = format!("{:x}", Sha256::digest(bytes));
+
+    if expected != actual {
+        return Err(anyhow!("Контролната сума не съвпада: очаквана {}, получена {}", expected, actual));
+    }
+
+    Ok(())
+}
+
+fn extract_binary(archive_name: &str, bytes: &[u8]) -> ::anyhow::Result<Vec<u8>> {
+    let mut binary = Vec::new();
+
+    if archive_name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.name().rsplit('/').next() == Some(BINARY_NAME) {
+                file.read_to_end(&mut binary)?;
+                return Ok(binary);
+            }
+        }
+    } else {
+        let mut tar_bytes = Vec::new();
+        lzma_rs::xz_decompress(&mut Cursor::new(bytes), &mut tar_bytes).
+            map_err(|e| anyhow!("Couldn't decompress {}: {}", archive_name, e))?;
+
+        let mut archive = tar::Archive::new(Cursor::new(tar_bytes));
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.file_name().and_then(|n| n.to_str()) == Some(BINARY_NAME) {
+                entry.read_to_end(&mut binary)?;
+                return Ok(binary);
+            }
+        }
+    }
+
+    Err(anyhow!("Няма `{}` в {}", BINARY_NAME, archive_name))
+}
@@ -0,0 +1,63 @@
+use semver::Version;
+use serde::Deserialize;
+
+/// The version of this client.
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The format of the keylog this client uploads. The server bumps its own when it starts expecting
+/// something different, and clients with an older one can't upload anymore.
+pub const KEYLOG_FORMAT: u32 = 1;
+
+/// What the server advertises about the clients it supports. Older servers don't advertise
+/// anything, so every field is optional.
+///
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ClientVersions {
+    pub minimum: Option<String>,
+    pub latest: Option<String>,
+    pub keylog_format: Option<u32>,
+    /// Set by servers that still expect the token in URLs and request bodies, and assumed for the
+    /// ones that are too old to have this endpoint
+    #[serde(default)]
+    pub legacy_auth: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionStatus {
+    UpToDate,
+    /// Still supported, but there's a newer version
+    Outdated { latest: String },
+    /// Too old to upload solutions
+    Unsupported { reason: String },
+}
+
+impl ClientVersions {
+    pub fn status(&self) -> VersionStatus {
+        // Unwrap: the package version is always valid semver
+        let current = Version::parse(CLIENT_VERSION).unwrap();
+
+        if let Some(keylog_format) = self.keylog_format {
+            if keylog_format != KEYLOG_FORMAT {
+                return VersionStatus::Unsupported {
+                    reason: format!("сайтът очаква друг формат на клавишите ({})", keylog_format),
+                };
+            }
+        }
+
+        if let Some(minimum) = self.minimum.as_deref().and_then(|v| Version::parse(v).ok()) {
+            if current < minimum {
+                return VersionStatus::Unsupported {
+                    reason: format!("минималната версия е {}, а твоята е {}", minimum, current),
+                };
+            }
+        }
+
+        if let Some(latest) = self.latest.as_deref().and_then(|v| Version::parse(v).ok()) {
+            if current < latest {
+                return VersionStatus::Outdated { latest: latest.to_string() };
+            }
+        }
+
+        VersionStatus::UpToDate
+    }
+}
@@ -1,36 +1,178 @@
 use std::fs;
 use std::path::{PathBuf, Path};
-use std::collections::HashMap;
-use std::process::Command;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::io;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tracing::{debug, trace, Level};
 use which::which;
 
+use crate::config::{VimConfig, VimInterface};
+
+// Extra arguments are limited to ones that change how Vim looks, not how it behaves, so nothing can
+// load scripts, plugins or a different vimrc, or mess with the keylog
+const ALLOWED_FLAGS: &[&str] = &["-f", "-X", "--not-a-term", "-reverse", "-rv", "-iconic"];
+const ALLOWED_ARGS_WITH_VALUE: &[&str] = &[
+    "-T", "-geometry", "-geom", "-font", "-fn", "-background", "-bg", "-foreground", "-fg",
+    "-display", "--role",
+];
+
+// The only variables Vim gets from the environment, the ones it needs to talk to the terminal or
+// the display. Anything else, like $VIMINIT, $MYVIMRC, $VIMRUNTIME or the XDG directories, could
+// bring in the user's own config.
+const PASSTHROUGH_ENV: &[&str] = &[
+    "PATH", "TERM", "COLORTERM", "TERM_PROGRAM", "TERMINFO", "TERMINFO_DIRS",
+    "DISPLAY", "WAYLAND_DISPLAY", "XAUTHORITY", "XDG_RUNTIME_DIR", "DBUS_SESSION_BUS_ADDRESS",
+    "LANG", "LANGUAGE", "LC_ALL", "LC_CTYPE", "LC_MESSAGES", "TZ", "TMPDIR", "USER", "LOGNAME",
+    "SYSTEMROOT", "SYSTEMDRIVE", "WINDIR", "COMSPEC", "PATHEXT", "TEMP", "TMP", "USERNAME",
+];
+
+const ALLOWED_ENV: &[&str] = &["TERM", "COLORTERM", "DISPLAY", "LANG", "LC_ALL", "LC_CTYPE", "LC_MESSAGES"];
+
+// Executables that open a window instead of running in the terminal
+const GUI_EXECUTABLES: &[&str] = &["mvim", "gvim"];
+
+const SCRIPTED_TIMEOUT: Duration = Duration::from_secs(10);
+// How long Vim has to close itself after the time limit, before it's killed
+const TIME_LIMIT_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum VimError {
+    #[error("Не беше намерен {0}, вижте дали програмата е в $PATH")]
+    NotFound(String),
+
+    #[error("`{0} --version` не проработи, това май не е Vim: {1}")]
+    NoVersion(String, String),
+
+    #[error("Vim излезе с неуспешен статус ({0}).")]
+    Failed(ExitStatus),
+}
+
 pub struct Vim {
     pub executable: String,
+    pub flavor: VimFlavor,
+    /// Missing for GUI Vims on Windows, see `Vim::new`
+    pub info: Option<VimInfo>,
     vimrc_path: PathBuf,
+    home_path: PathBuf,
+    extra_args: Vec<String>,
+    extra_env: BTreeMap<String, String>,
+    size: Option<(u32, u32)>,
 }
 
 impl Vim {
-    pub fn new(vimrc_path: PathBuf) -> ::anyhow::Result<Self> {
+    pub fn new(vimrc_path: PathBuf, home_path: PathBuf, config: &VimConfig) -> ::anyhow::Result<Self> {
+        validate_args(&config.args)?;
+        validate_env(&config.env)?;
+
         let executable =
-            if let Ok(custom_value) = std::env::var("VIMFMI_EXECUTABLE") {
-                custom_value
-            } else if which("mvim").is_ok() {
-                String::from("mvim")
-            } else if which("gvim").is_ok() {
-                String::from("gvim")
-            } else if which("vim").is_ok() {
-                String::from("vim")
+            if let Some(configured) = &config.executable {
+                configured.clone()
             } else {
-                return Err(anyhow!("Не беше намерен нито `mvim`, нито `gvim`, нито `vim`, вижте дали програмата е в $PATH"));
+                find_executable(config)?
             };
 
-        Ok(Self { executable, vimrc_path })
+        if which(&executable).is_err() {
+            return Err(VimError::NotFound(format!("`{}`", executable)).into());
+        }
+        debug!(%executable, "found vim");
+
+        // On Windows, `gvim --version` shows a dialog instead of printing anything, so it's trusted
+        // to be Vim there
+        let info =
+            if cfg!(windows) && is_gui(&executable) {
+                None
+            } else {
+                let info = VimInfo::detect(&executable).
+                    map_err(|e| VimError::NoVersion(executable.clone(), format!("{:#}", e)))?;
+                Some(info)
+            };
+        let flavor = info.as_ref().map_or_else(|| VimFlavor::guess(&executable), |info| info.flavor);
+
+        Ok(Self {
+            executable,
+            flavor,
+            info,
+            vimrc_path,
+            home_path,
+            extra_args: config.args.clone(),
+            extra_env: config.env.clone(),
+            size: config.columns.zip(config.lines),
+        })
+    }
+
+    /// Runs an interactive session. With a `time_limit`, the session timer in the vimrc should
+    /// close Vim on time, but if it doesn't (no timers, a stuck prompt), Vim is killed a little
+    /// later. The last value is whether that happened, and then the result is whatever was saved
+    /// until then.
+    ///
+    pub fn run(
+        &self,
+        input_path: &Path,
+        log_path: &Path,
+        time_limit: Option<Duration>,
+    ) -> ::anyhow::Result<(String, Vec<u8>, bool)> {
+        let mut child = self.spawn(&mut self.command(input_path, log_path, &[]))?;
+        let status = match time_limit {
+            Some(time_limit) => wait_with_timeout(&mut child, time_limit + TIME_LIMIT_GRACE)?,
+            None => Some(child.wait()?),
+        };
+
+        let killed = match status {
+            Some(status) => {
+                debug!(%status, "vim exited");
+                if !status.success() {
+                    return Err(VimError::Failed(status).into());
+                }
+                false
+            },
+            None => {
+                debug!("vim was killed after the time limit");
+                true
+            },
+        };
+
+        let (output, log_bytes) = read_session(input_path, log_path)?;
+        Ok((output, log_bytes, killed))
+    }
+
+    /// Runs a session without anyone at the keyboard. The `keys` are in the notation of a
+    /// double-quoted Vim string (`\<Esc>`) and are fed in as if they were typed, so they end up in
+    /// the keylog. They should finish by writing the file and quitting.
+    ///
+    /// Nothing is shown on the screen, and Vim is killed if it doesn't quit in time.
+    ///
+    pub fn run_scripted(
+        &self,
+        input_path: &Path,
+        log_path: &Path,
+        keys: &str,
+    ) -> ::anyhow::Result<(String, Vec<u8>)> {
+        let feedkeys = format!("call feedkeys(\"{}\", 't')", keys);
+        let mut command = self.command(input_path, log_path, &["-c", &feedkeys]);
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        let mut child = self.spawn(&mut command)?;
+
+        // Vim waits a couple of seconds after warning that it's not running in a terminal
+        let status = wait_with_timeout(&mut child, SCRIPTED_TIMEOUT)?.ok_or_else(|| {
+            anyhow!("Vim не приключи за {} секунди и беше спрян", SCRIPTED_TIMEOUT.as_secs())
+        })?;
+        debug!(%status, "vim exited");
+        if !status.success() {
+            return Err(VimError::Failed(status).into());
+        }
+
+        read_session(input_path, log_path)
     }
 
-    pub fn run(&self, input_path: &Path, log_path: &Path) -> ::anyhow::Result<(String, Vec<u8>)> {
+    fn command(&self, input_path: &Path, log_path: &Path, internal_args: &[&str]) -> Command {
         // -Z         - restricted mode, utilities not allowed
         // -n         - no swap file, memory only editing
         // --noplugin - don't load any plugins, lets be fair!
@@ -40,28 +182,314 @@ impl Vim {
         // -u vimrc   - load vimgolf .vimrc to level the playing field
         // -U NONE    - don't load .gvimrc
         // -W logfile - keylog file (overwrites if already exists)
+        //
+        // Neovim doesn't fork and has no .gvimrc, but it has a lot more places to load things from:
+        //
+        // --clean    - no user config or data directories in the runtimepath, no environment
+        //              variables like $VIMINIT, and no shada file
+        // -i NONE    - no shada file, just in case
         let mut command = Command::new(&self.executable);
-        let mut command = &mut command;
 
-        if self.executable != "nvim" {
-            command = command.args(["--nofork", "-Z"]);
+        match self.flavor {
+            VimFlavor::Vim => {
+                command.args(["--nofork", "-Z", "-n", "--noplugin", "-i", "NONE", "+0", "-U", "NONE"]);
+            },
+            VimFlavor::Neovim => {
+                command.args(["--clean", "-Z", "-n", "--noplugin", "-i", "NONE", "+0"]);
+            },
         }
 
-        command = command.
-            args(["-n", "--noplugin", "-i", "NONE", "+0", "-U", "NONE"]).
+        command.
             args(["-u", self.vimrc_path.to_str().unwrap()]).
             args(["-W", log_path.to_str().unwrap()]).
-            arg(input_path.to_str().unwrap());
+            args(&self.extra_args).
+            args(internal_args);
+
+        self.set_env(&mut command);
+
+        // Commands from -c run after the vimrc, so it can't change the size back
+        if let Some((columns, lines)) = self.size {
+            command.args(["-c", &format!("set columns={} lines={}", columns, lines)]);
+        }
+
+        command.arg(input_path.to_str().unwrap());
+
+        if tracing::enabled!(Level::TRACE) {
+            let vimrc = fs::read_to_string(&self.vimrc_path).unwrap_or_default();
+            trace!(path = %self.vimrc_path.display(), "final vimrc:\n{}", vimrc);
+        }
+        debug!(?command, "starting vim");
+
+        command
+    }
+
+    // The executable was there when it was looked up, but it could be gone (or broken) by now
+    fn spawn(&self, command: &mut Command) -> ::anyhow::Result<Child> {
+        command.spawn().map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+                VimError::NotFound(format!("`{}` ({})", self.executable, e)).into()
+            },
+            _ => e.into(),
+        })
+    }
+
+    /// Leaves out everything but a few variables, and points the home and XDG directories to the
+    /// session's empty home.
+    ///
+    fn set_env(&self, command: &mut Command) {
+        command.env_clear();
 
-        if !command.spawn()?.wait()?.success() {
-            return Err(anyhow!("Vim излезе с неуспешен статус."));
+        for name in PASSTHROUGH_ENV {
+            if let Some(value) = env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+
+        // X11 looks for its credentials in the home directory, and gvim can't open a window without
+        // them
+        if env::var_os("XAUTHORITY").is_none() {
+            if let Some(home) = env::var_os("HOME") {
+                let xauthority = Path::new(&home).join(".Xauthority");
+                if xauthority.exists() {
+                    command.env("XAUTHORITY", xauthority);
+                }
+            }
+        }
+
+        let home = &self.home_path;
+        command.
+            env("HOME", home).
+            env("XDG_CONFIG_HOME", home.join(".config")).
+            env("XDG_DATA_HOME", home.join(".local/share")).
+            env("XDG_STATE_HOME", home.join(".local/state")).
+            env("XDG_CACHE_HOME", home.join(".cache"));
+
+        if cfg!(windows) {
+            command.
+                env("USERPROFILE", home).
+                env("APPDATA", home.join("AppData/Roaming")).
+                env("LOCALAPPDATA", home.join("AppData/Local"));
+        }
+
+        command.envs(&self.extra_env);
+    }
+}
+
+pub fn validate_args(args: &[String]) -> ::anyhow::Result<()> {
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if ALLOWED_ARGS_WITH_VALUE.contains(&arg.as_str()) {
+            // Whatever comes next is a value, even if it looks like a flag
+            if args.next().is_none() {
+                return Err(anyhow!("Аргументът `{}` на Vim изисква стойност", arg));
+            }
+        } else if !ALLOWED_FLAGS.contains(&arg.as_str()) {
+            return Err(anyhow!(
+                "Аргументът `{}` не е позволен за Vim, позволени са: {} {}",
+                arg,
+                ALLOWED_FLAGS.join(" "),
+                ALLOWED_ARGS_WITH_VALUE.join(" "),
+            ));
         }
+    }
+
+    Ok(())
+}
 
-        let result = fs::read_to_string(input_path)?;
-        let log = fs::read(log_path)?;
+pub(crate) fn validate_env(env: &BTreeMap<String, String>) -> ::anyhow::Result<()> {
+    for name in env.keys() {
+        if !ALLOWED_ENV.contains(&name.as_str()) {
+            return Err(anyhow!(
+                "Променливата `{}` не може да се задава на Vim, позволени са: {}",
+                name,
+                ALLOWED_ENV.join(" "),
+            ));
+        }
+    }
+
+    Ok(())
+}
 
-        Ok((result, log))
+/// The first executable from the preference list that's in the `$PATH` and fits the interface.
+fn find_executable(config: &VimConfig) -> Result<String, VimError> {
+    let candidates: Vec<&String> = config.preference.iter().
+        filter(|name| match config.interface {
+            VimInterface::Any => true,
+            VimInterface::Terminal => !is_gui(name),
+            VimInterface::Gui => is_gui(name),
+        }).
+        collect();
+
+    if let Some(found) = candidates.iter().find(|name| which(name).is_ok()) {
+        return Ok(found.to_string());
     }
+
+    let names: Vec<String> = candidates.iter().map(|name| format!("`{}`", name)).collect();
+    if names.is_empty() {
+        Err(VimError::NotFound(String::from("подходящ Vim в `vim.preference`")))
+    } else {
+        Err(VimError::NotFound(format!("нито един от {}", names.join(", "))))
+    }
+}
+
+fn executable_stem(executable: &str) -> &str {
+    Path::new(executable).file_stem().and_then(|n| n.to_str()).unwrap_or_default()
+}
+
+fn is_gui(executable: &str) -> bool {
+    GUI_EXECUTABLES.contains(&executable_stem(executable))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VimFlavor {
+    Vim,
+    Neovim,
+}
+
+impl VimFlavor {
+    /// Without a version to go by, only the name is left: `nvim`, `nvim-qt`, `nvim.exe`.
+    pub fn guess(executable: &str) -> Self {
+        if executable_stem(executable).starts_with("nvim") {
+            VimFlavor::Neovim
+        } else {
+            VimFlavor::Vim
+        }
+    }
+}
+
+/// What `--version` says about a Vim build. Which keycodes end up in the keylog depends on the
+/// version and patch level, so the server wants to know them too.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VimInfo {
+    pub flavor: VimFlavor,
+    pub major: u32,
+    pub minor: u32,
+    /// For Vim, the last patch of the first range in "Included patches", since distributions
+    /// sometimes add a few later ones on top. For Neovim, the patch part of the version.
+    pub patch: Option<u32>,
+    pub gui: bool,
+    /// The features listed with a `+`, without it, like `clipboard` or `terminal`
+    pub features: Vec<String>,
+}
+
+impl VimInfo {
+    /// Runs `--version` and makes sense of its output.
+    pub fn detect(executable: &str) -> ::anyhow::Result<Self> {
+        let output = Command::new(executable).arg("--version").output()?;
+        if !output.status.success() {
+            return Err(VimError::Failed(output.status).into());
+        }
+
+        let version = String::from_utf8_lossy(&output.stdout);
+        let info = Self::parse(&version).
+            ok_or_else(|| anyhow!("Неразпознат изход от `{} --version`", executable))?;
+        debug!(?info, "vim version");
+
+        Ok(info)
+    }
+
+    pub fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines();
+        let first_line = lines.next()?.trim();
+
+        if let Some(version) = first_line.strip_prefix("NVIM v") {
+            // NVIM v0.9.5, possibly with a suffix like -dev-1234+g5678
+            let mut numbers = version.split(|c: char| !c.is_ascii_digit());
+
+            return Some(Self {
+                flavor: VimFlavor::Neovim,
+                major: numbers.next()?.parse().ok()?,
+                minor: numbers.next()?.parse().ok()?,
+                patch: numbers.next().and_then(|n| n.parse().ok()),
+                gui: false,
+                features: Vec::new(),
+            });
+        }
+
+        // VIM - Vi IMproved 9.0 (2022 Jun 28, compiled Feb 16 2025 05:23:41)
+        let (_, version) = first_line.split_once("IMproved ")?;
+        let version = version.split_whitespace().next()?;
+        let (major, minor) = version.split_once('.')?;
+
+        let patch = lines.
+            find_map(|line| line.strip_prefix("Included patches: ")).
+            and_then(|patches| {
+                let first_range = patches.split(',').next()?.trim();
+                let last = first_range.rsplit('-').next()?;
+                last.parse().ok()
+            });
+
+        // "Huge version with GTK3 GUI." or "Huge version without GUI."
+        let gui = output.lines().any(|line| line.contains(" version with ") && line.contains("GUI"));
+        let features = output.
+            split_whitespace().
+            filter_map(|word| word.strip_prefix('+')).
+            map(String::from).
+            collect();
+
+        Some(Self {
+            flavor: VimFlavor::Vim,
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            patch,
+            gui,
+            features,
+        })
+    }
+
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+impl fmt::Display for VimInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.flavor {
+            VimFlavor::Vim => "Vim",
+            VimFlavor::Neovim => "Neovim",
+        };
+
+        write!(f, "{} {}.{}", name, self.major, self.minor)?;
+        if let Some(patch) = self.patch {
+            match self.flavor {
+                VimFlavor::Vim => write!(f, ", patch {}", patch)?,
+                VimFlavor::Neovim => write!(f, ".{}", patch)?,
+            }
+        }
+        if self.gui {
+            write!(f, ", GUI")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The exit status, or nothing if the child had to be killed.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> ::anyhow::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            // Reap it, so the files aren't read while it's still writing them
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn read_session(input_path: &Path, log_path: &Path) -> ::anyhow::Result<(String, Vec<u8>)> {
+    let result = fs::read_to_string(input_path)?;
+    let log = fs::read(log_path)?;
+
+    Ok((result, log))
 }
 
 #[derive(Debug)]
@@ -353,3 +781,68 @@ fn kc_mbyte() -> &'static HashMap<Vec<u8>, String> {
         data
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIM_VERSION: &str = "\
+VIM - Vi IMproved 9.0 (2022 Jun 28, compiled Feb 16 2025 05:23:41)
+Included patches: 1-1378, 1499, 1532
+Modified by team+vim@tracker.debian.org
+Huge version with GTK3 GUI.  Features included (+) or not (-):
++acl               +file_in_path      +mouse_urxvt       -tag_any_white
++autocmd           +float             -mzscheme          +terminal
+";
+
+    #[test]
+    fn vim_version() {
+        let info = VimInfo::parse(VIM_VERSION).unwrap();
+
+        assert_eq!(info.flavor, VimFlavor::Vim);
+        assert_eq!((info.major, info.minor), (9, 0));
+        assert_eq!(info.patch, Some(1378));
+        assert!(info.gui);
+        assert!(info.has("autocmd"));
+        assert!(info.has("terminal"));
+        assert!(!info.has("mzscheme"));
+        assert_eq!(info.to_string(), "Vim 9.0, patch 1378, GUI");
+    }
+
+    #[test]
+    fn vim_version_without_gui_or_patches() {
+        let output = "VIM - Vi IMproved 8.2 (2019 Dec 12, compiled Apr 18 2022 19:26:30)\n\
+                      Small version without GUI.  Features included (+) or not (-):\n";
+        let info = VimInfo::parse(output).unwrap();
+
+        assert_eq!((info.major, info.minor), (8, 2));
+        assert_eq!(info.patch, None);
+        assert!(!info.gui);
+    }
+
+    #[test]
+    fn neovim_version() {
+        let info = VimInfo::parse("NVIM v0.9.5\nBuild type: Release\n").unwrap();
+
+        assert_eq!(info.flavor, VimFlavor::Neovim);
+        assert_eq!((info.major, info.minor, info.patch), (0, 9, Some(5)));
+        assert_eq!(info.to_string(), "Neovim 0.9.5");
+
+        let info = VimInfo::parse("NVIM v0.10.0-dev-1234+g5678abc\n").unwrap();
+        assert_eq!((info.major, info.minor, info.patch), (0, 10, Some(0)));
+    }
+
+    #[test]
+    fn unknown_version() {
+        assert_eq!(VimInfo::parse(""), None);
+        assert_eq!(VimInfo::parse("nano 7.2\n"), None);
+        assert_eq!(VimInfo::parse("VIM - Vi IMproved nine\n"), None);
+    }
+
+    #[test]
+    fn flavor_from_the_name() {
+        assert_eq!(VimFlavor::guess("nvim"), VimFlavor::Neovim);
+        assert_eq!(VimFlavor::guess("/usr/local/bin/nvim-qt"), VimFlavor::Neovim);
+        assert_eq!(VimFlavor::guess("gvim.exe"), VimFlavor::Vim);
+    }
+}
@@ -0,0 +1,207 @@
+//! Solutions have to be made with nothing but the vimgolf vimrc, so these start sessions with a
+//! home directory and environment full of config and plugins that leave a marker file when they're
+//! loaded.
+//!
+//! The environment is only set for a child process running `isolated_session`, since changing it
+//! in this one would race with the other tests. On its own, `isolated_session` has nothing to do.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempfile::TempDir;
+use url::Url;
+
+use vim_fmi::config::{Config, Profile, VimConfig};
+use vim_fmi::controller::Controller;
+use vim_fmi::report::Keys;
+use vim_fmi::vim::{Vim, VimFlavor};
+
+const SCRIPT: &str = r"ihello\<Esc>:wq\<CR>";
+const EXPECTED_KEYS: &str = "ihello<Esc>:wq<CR>";
+
+const HOME_VAR: &str = "VIMFMI_TEST_HOME";
+const EXECUTABLE_VAR: &str = "VIMFMI_TEST_EXECUTABLE";
+
+fn marker(home: &Path, name: &str) -> String {
+    // Vim gets its own $HOME, so the markers go to an absolute path
+    let path = home.join(format!("marker-{}", name));
+    format!("call writefile(['loaded'], '{}')\n", path.display())
+}
+
+fn create_home() -> TempDir {
+    let home = TempDir::new().unwrap();
+    let write = |path: &str, name: &str| {
+        let path = home.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, marker(home.path(), name)).unwrap();
+    };
+
+    write(".vimrc", "vimrc");
+    write(".gvimrc", "gvimrc");
+    write(".vim/plugin/evil.vim", "vim-plugin");
+    write(".vim/after/plugin/evil.vim", "vim-after-plugin");
+    write(".vim/after/syntax/text.vim", "vim-after-syntax");
+    write(".config/nvim/init.vim", "nvim-init");
+    write(".config/nvim/plugin/evil.vim", "nvim-plugin");
+    write(".local/share/nvim/site/plugin/evil.vim", "nvim-site-plugin");
+
+    // Config in unusual places, found only through environment variables
+    write("xdg-config/nvim/init.vim", "xdg-nvim-init");
+    write("xdg-data/nvim/site/plugin/evil.vim", "xdg-nvim-site-plugin");
+    write("vim/vimrc", "system-vimrc");
+    write("runtime/syntax/syntax.vim", "runtime-syntax");
+    write("runtime/filetype.vim", "runtime-filetype");
+    write("runtime/indent.vim", "runtime-indent");
+    write("runtime/plugin/evil.vim", "runtime-plugin");
+
+    home
+}
+
+fn leaky_env(home: &Path) -> Vec<(&'static str, OsString)> {
+    vec![
+        ("HOME", home.into()),
+        ("VIMINIT", marker(home, "viminit").into()),
+        ("EXINIT", marker(home, "exinit").into()),
+        ("MYVIMRC", home.join(".vimrc").into()),
+        ("VIM", home.join("vim").into()),
+        ("VIMRUNTIME", home.join("runtime").into()),
+        ("XDG_CONFIG_HOME", home.join("xdg-config").into()),
+        ("XDG_DATA_HOME", home.join("xdg-data").into()),
+        ("XDG_STATE_HOME", home.join("xdg-state").into()),
+    ]
+}
+
+/// Runs `isolated_session` in a separate test process with a home full of config and all the
+/// environment variables pointing to more of it.
+///
+fn assert_isolated(executable: &str) {
+    let home = create_home();
+    let output = Command::new(env::current_exe().unwrap()).
+        args(["--exact", "isolated_session", "--nocapture"]).
+        envs(leaky_env(home.path())).
+        env(HOME_VAR, home.path()).
+        env(EXECUTABLE_VAR, executable).
+        output().
+        unwrap();
+
+    assert!(
+        output.status.success(),
+        "isolated session with {} failed:\n{}{}",
+        executable,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr),
+    );
+    // A filter that matches nothing passes too
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+}
+
+fn controller(data_dir: PathBuf) -> Controller {
+    let profile = Profile {
+        name: String::from("test"),
+        host: Url::parse("http://localhost:3000").unwrap(),
+        data_dir,
+    };
+
+    Controller::new(&profile, &Config::default()).unwrap()
+}
+
+fn vim(controller: &Controller, executable: &str) -> Vim {
+    let config = VimConfig { executable: Some(executable.to_owned()), ..VimConfig::default() };
+    Vim::new(controller.vimrc_path(), controller.home_path().unwrap(), &config).unwrap()
+}
+
+#[test]
+fn isolated_session() {
+    // Only started by `assert_isolated`, in a process of its own
+    let (Some(home), Ok(executable)) = (env::var_os(HOME_VAR), env::var(EXECUTABLE_VAR)) else {
+        return;
+    };
+    let home = PathBuf::from(home);
+
+    let controller = controller(home.join("data"));
+    let vim = vim(&controller, &executable);
+
+    let input_path = controller.create_file("input.txt", "").unwrap();
+    let log_path = controller.create_file("log", "").unwrap();
+    let (output, log_bytes) = vim.run_scripted(&input_path, &log_path, SCRIPT).unwrap();
+
+    let markers: Vec<_> = fs::read_dir(&home).unwrap().
+        map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).
+        filter(|name| name.starts_with("marker-")).
+        collect();
+    assert_eq!(markers, Vec::<String>::new(), "{} loaded user files", executable);
+
+    assert!(!home.join(".viminfo").exists(), "{} wrote a viminfo file", executable);
+    assert!(!home.join(".local/state/nvim/shada").exists(), "{} wrote a shada file", executable);
+    assert!(!home.join(".local/share/nvim/shada").exists(), "{} wrote a shada file", executable);
+    assert!(!home.join("xdg-state").exists(), "{} wrote a shada file", executable);
+
+    // An error in the vimrc would show a prompt that eats up keys
+    assert_eq!(output.trim(), "hello");
+    assert_eq!(Keys::from_log(&log_bytes).script, EXPECTED_KEYS);
+
+    let input_path = controller.create_file("input.txt", "").unwrap();
+    let script = r"i\<C-R>=$HOME\<CR> \<C-R>=$VIMRUNTIME\<CR>\<Esc>:wq\<CR>";
+    let (output, _) = vim.run_scripted(&input_path, &log_path, script).unwrap();
+
+    let (session_home, runtime) = output.trim().split_once(' ').unwrap();
+    assert_eq!(Path::new(session_home), controller.home_path().unwrap());
+    // Vim's own runtime, not the one from the environment
+    assert!(Path::new(runtime).join("syntax/syntax.vim").exists(), "unexpected runtime {}", runtime);
+}
+
+#[test]
+fn vim_is_isolated() {
+    assert_isolated("vim");
+}
+
+#[test]
+fn neovim_is_isolated() {
+    if which::which("nvim").is_err() {
+        eprintln!("Skipping: there's no `nvim` in the $PATH");
+        return;
+    }
+
+    assert_isolated("nvim");
+}
+
+#[cfg(unix)]
+#[test]
+fn neovim_gets_its_own_arguments() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let args_path = dir.path().join("args");
+    let executable = dir.path().join("editor");
+
+    // Not named `nvim`, so it has to be recognized by its version
+    fs::write(&executable, format!(
+        "#!/bin/sh\n\
+         [ \"$1\" = --version ] && {{ echo 'NVIM v0.9.5'; exit 0; }}\n\
+         printf '%s\\n' \"$@\" > '{}'\n",
+        args_path.display(),
+    )).unwrap();
+    fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let controller = controller(dir.path().join("data"));
+    let vim = vim(&controller, executable.to_str().unwrap());
+    assert_eq!(vim.flavor, VimFlavor::Neovim);
+
+    let input_path = controller.create_file("input.txt", "").unwrap();
+    let log_path = controller.create_file("log", "").unwrap();
+    vim.run_scripted(&input_path, &log_path, SCRIPT).unwrap();
+
+    let args = fs::read_to_string(&args_path).unwrap();
+    let args: Vec<&str> = args.lines().collect();
+
+    for expected in ["--clean", "-Z", "-n", "--noplugin"] {
+        assert!(args.contains(&expected), "missing {} in {:?}", expected, args);
+    }
+    assert!(args.windows(2).any(|pair| pair == ["-i", "NONE"]), "shada isn't off in {:?}", args);
+    assert!(args.windows(2).any(|pair| pair[0] == "-u" && Path::new(pair[1]) == controller.vimrc_path()));
+    assert!(!args.contains(&"--nofork"));
+    assert!(!args.contains(&"-U"));
+}